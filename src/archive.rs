@@ -0,0 +1,83 @@
+//! ZIP packaging for multi-file conversion results
+
+use crate::error::ConvertError;
+use crate::layers::{Layer, LayerKind};
+use serde::Serialize;
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// In-memory ZIP archive builder
+///
+/// Entries are stored uncompressed: JPEG and the other output formats are
+/// already compressed, so deflate would only burn CPU.
+pub struct ZipArchive {
+    writer: ZipWriter<Cursor<Vec<u8>>>,
+}
+
+impl ZipArchive {
+    pub fn new() -> Self {
+        Self {
+            writer: ZipWriter::new(Cursor::new(Vec::new())),
+        }
+    }
+
+    /// Append a file to the archive
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<(), ConvertError> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        self.writer
+            .start_file(name, options)
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+        self.writer
+            .write_all(data)
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))
+    }
+
+    /// Finish the archive and return its bytes
+    pub fn finish(self) -> Result<Vec<u8>, ConvertError> {
+        self.writer
+            .finish()
+            .map(Cursor::into_inner)
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))
+    }
+}
+
+/// Index of the files in a result archive, stored as `manifest.json`
+#[derive(Debug, Default, Serialize)]
+pub struct Manifest {
+    pub images: Vec<ManifestImage>,
+}
+
+/// A converted top-level image and the layers exported with it
+#[derive(Debug, Serialize)]
+pub struct ManifestImage {
+    /// Position among the file's top-level images
+    pub index: usize,
+    pub file: String,
+    pub layers: Vec<ManifestLayer>,
+}
+
+/// An exported depth or auxiliary layer
+#[derive(Debug, Serialize)]
+pub struct ManifestLayer {
+    pub file: String,
+    pub kind: LayerKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aux_type: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+}
+
+impl ManifestLayer {
+    pub fn new(file: String, layer: &Layer) -> Self {
+        Self {
+            file,
+            kind: layer.kind,
+            aux_type: layer.aux_type.clone(),
+            width: layer.width,
+            height: layer.height,
+            bit_depth: layer.bit_depth,
+        }
+    }
+}
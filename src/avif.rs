@@ -0,0 +1,190 @@
+//! Lossless AVIF encoding through libheif's C API
+//!
+//! libheif converts RGB input to YCbCr with the output nclx matrix, BT.601
+//! by default, which rounds. Lossless output has to be tagged as full-range
+//! identity (GBR) so the RGB samples are coded as is. libheif-rs can attach
+//! an nclx profile but cannot set its matrix or range, so this path builds
+//! the image, the profile and the encoding context with libheif-sys.
+
+use crate::error::ConvertError;
+use libheif_sys as lh;
+use std::ffi::{c_int, c_void, CStr};
+use std::ptr;
+
+/// Runs a release function when dropped
+struct Release<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for Release<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
+fn check(err: lh::heif_error) -> Result<(), ConvertError> {
+    if err.code == lh::heif_error_code_heif_error_Ok {
+        return Ok(());
+    }
+    // libheif always sets a message, static for the error's lifetime
+    let message = unsafe { CStr::from_ptr(err.message) };
+    Err(ConvertError::EncodeError(
+        message.to_string_lossy().into_owned(),
+    ))
+}
+
+fn alloc_error(what: &str) -> ConvertError {
+    ConvertError::EncodeError(format!("Failed to allocate {}", what))
+}
+
+/// Encode interleaved 8-bit RGB or RGBA rows as lossless 4:4:4 AVIF
+///
+/// `icc` is embedded next to the nclx profile, which decoders still need
+/// for the matrix.
+pub fn encode_lossless(
+    (data, stride): (&[u8], usize),
+    (width, height): (u32, u32),
+    has_alpha: bool,
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+    speed: u8,
+) -> Result<Vec<u8>, ConvertError> {
+    let (chroma, channels) = if has_alpha {
+        (lh::heif_chroma_heif_chroma_interleaved_RGBA, 4)
+    } else {
+        (lh::heif_chroma_heif_chroma_interleaved_RGB, 3)
+    };
+    let row_len = width as usize * channels;
+
+    // Every pointer is checked before use and released once, after the
+    // objects created from it (locals drop in reverse order)
+    unsafe {
+        let ctx = lh::heif_context_alloc();
+        if ctx.is_null() {
+            return Err(alloc_error("HEIF context"));
+        }
+        let _ctx = Release(move || lh::heif_context_free(ctx));
+
+        let mut encoder = ptr::null_mut();
+        check(lh::heif_context_get_encoder_for_format(
+            ctx,
+            lh::heif_compression_format_heif_compression_AV1,
+            &mut encoder,
+        ))?;
+        let _encoder = Release(move || lh::heif_encoder_release(encoder));
+        check(lh::heif_encoder_set_lossless(encoder, 1))?;
+        check(lh::heif_encoder_set_parameter_string(
+            encoder,
+            c"chroma".as_ptr(),
+            c"444".as_ptr(),
+        ))?;
+        check(lh::heif_encoder_set_parameter_integer(
+            encoder,
+            c"speed".as_ptr(),
+            speed as c_int,
+        ))?;
+
+        let mut image = ptr::null_mut();
+        check(lh::heif_image_create(
+            width as c_int,
+            height as c_int,
+            lh::heif_colorspace_heif_colorspace_RGB,
+            chroma,
+            &mut image,
+        ))?;
+        let _image = Release(move || lh::heif_image_release(image));
+        check(lh::heif_image_add_plane(
+            image,
+            lh::heif_channel_heif_channel_interleaved,
+            width as c_int,
+            height as c_int,
+            8,
+        ))?;
+        let mut plane_stride: c_int = 0;
+        let plane = lh::heif_image_get_plane(
+            image,
+            lh::heif_channel_heif_channel_interleaved,
+            &mut plane_stride,
+        );
+        if plane.is_null() {
+            return Err(alloc_error("interleaved plane"));
+        }
+        let plane_stride = plane_stride as usize;
+        let plane = std::slice::from_raw_parts_mut(plane, plane_stride * height as usize);
+        for (dst, src) in plane.chunks_mut(plane_stride).zip(data.chunks(stride)) {
+            dst[..row_len].copy_from_slice(&src[..row_len]);
+        }
+
+        if let Some(icc) = icc {
+            check(lh::heif_image_set_raw_color_profile(
+                image,
+                c"prof".as_ptr(),
+                icc.as_ptr().cast(),
+                icc.len(),
+            ))?;
+        }
+
+        // Allocated with sRGB primaries and transfer; only the matrix and
+        // range change
+        let nclx = lh::heif_nclx_color_profile_alloc();
+        if nclx.is_null() {
+            return Err(alloc_error("nclx profile"));
+        }
+        let _nclx = Release(move || lh::heif_nclx_color_profile_free(nclx));
+        (*nclx).matrix_coefficients = lh::heif_matrix_coefficients_heif_matrix_coefficients_RGB_GBR;
+        (*nclx).full_range_flag = 1;
+        check(lh::heif_image_set_nclx_color_profile(image, nclx))?;
+
+        let encoding_options = lh::heif_encoding_options_alloc();
+        if encoding_options.is_null() {
+            return Err(alloc_error("encoding options"));
+        }
+        let _encoding_options = Release(move || lh::heif_encoding_options_free(encoding_options));
+        (*encoding_options).output_nclx_profile = nclx;
+        (*encoding_options).save_two_colr_boxes_when_ICC_and_nclx_available = 1;
+
+        let mut handle = ptr::null_mut();
+        check(lh::heif_context_encode_image(
+            ctx,
+            image,
+            encoder,
+            encoding_options,
+            &mut handle,
+        ))?;
+        let _handle = Release(move || lh::heif_image_handle_release(handle));
+        if let Some(tiff) = exif {
+            check(lh::heif_context_add_exif_metadata(
+                ctx,
+                handle,
+                tiff.as_ptr().cast(),
+                tiff.len() as c_int,
+            ))?;
+        }
+
+        let mut output = Vec::new();
+        let mut writer = lh::heif_writer {
+            writer_api_version: 1,
+            write: Some(write_to_vec),
+        };
+        check(lh::heif_context_write(
+            ctx,
+            &mut writer,
+            (&mut output as *mut Vec<u8>).cast(),
+        ))?;
+        Ok(output)
+    }
+}
+
+/// `heif_writer` callback appending to the `Vec<u8>` passed as `userdata`
+unsafe extern "C" fn write_to_vec(
+    _ctx: *mut lh::heif_context,
+    data: *const c_void,
+    size: usize,
+    userdata: *mut c_void,
+) -> lh::heif_error {
+    let output = &mut *userdata.cast::<Vec<u8>>();
+    output.extend_from_slice(std::slice::from_raw_parts(data.cast::<u8>(), size));
+    lh::heif_error {
+        code: lh::heif_error_code_heif_error_Ok,
+        subcode: lh::heif_suberror_code_heif_suberror_Unspecified,
+        message: c"Success".as_ptr(),
+    }
+}
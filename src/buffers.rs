@@ -0,0 +1,169 @@
+//! Pooled byte buffers
+//!
+//! Pixel buffers and encoder scratch space are tens of megabytes each and
+//! used to be allocated afresh for every job. Released buffers go back to a
+//! global pool bounded by the `Config`; counters report how often a request
+//! could be served from the pool.
+
+use crate::config::Config;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+static POOL: OnceLock<BufferPool> = OnceLock::new();
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static REUSES: AtomicU64 = AtomicU64::new(0);
+static DISCARDS: AtomicU64 = AtomicU64::new(0);
+
+struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    /// Most buffers kept at once
+    max_buffers: usize,
+    /// Largest capacity worth keeping
+    max_buffer_bytes: usize,
+}
+
+impl BufferPool {
+    fn new(max_buffers: usize, max_buffer_bytes: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+            max_buffer_bytes,
+        }
+    }
+
+    /// Remove the smallest pooled buffer that holds at least `capacity` bytes
+    fn take(&self, capacity: usize) -> Option<Vec<u8>> {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        let best = buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, buf)| buf.capacity() >= capacity)
+            .min_by_key(|(_, buf)| buf.capacity())
+            .map(|(i, _)| i)?;
+        let mut buf = buffers.swap_remove(best);
+        buf.clear();
+        Some(buf)
+    }
+
+    /// Keep `buf` for reuse; returns false when it is dropped instead
+    fn give(&self, buf: Vec<u8>) -> bool {
+        if buf.capacity() > self.max_buffer_bytes {
+            return false;
+        }
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        if buffers.len() >= self.max_buffers {
+            return false;
+        }
+        buffers.push(buf);
+        true
+    }
+}
+
+/// Set up the global pool; without it every buffer is freshly allocated
+pub fn init(config: &Config) {
+    let _ = POOL.set(BufferPool::new(
+        config.buffer_pool_size,
+        config.buffer_pool_max_bytes,
+    ));
+}
+
+/// Take an empty buffer with room for at least `capacity` bytes
+pub fn take(capacity: usize) -> Vec<u8> {
+    if let Some(buf) = POOL.get().and_then(|pool| pool.take(capacity)) {
+        REUSES.fetch_add(1, Ordering::Relaxed);
+        return buf;
+    }
+
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(capacity as u64, Ordering::Relaxed);
+    Vec::with_capacity(capacity)
+}
+
+/// Take a zero-initialised buffer of exactly `len` bytes, e.g. as encoder
+/// output space
+pub fn take_filled(len: usize) -> Vec<u8> {
+    let mut buf = take(len);
+    buf.resize(len, 0);
+    buf
+}
+
+/// Hand a buffer back for reuse
+///
+/// Buffers are dropped when the pool is full or they exceed the configured
+/// size limit.
+pub fn give(buf: Vec<u8>) {
+    let Some(pool) = POOL.get() else {
+        return;
+    };
+    if buf.capacity() == 0 {
+        return;
+    }
+    if !pool.give(buf) {
+        DISCARDS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Allocation counters since startup
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    /// Buffers that had to be allocated
+    pub allocations: u64,
+    /// Bytes requested by those allocations
+    pub allocated_bytes: u64,
+    /// Requests served from the pool
+    pub reuses: u64,
+    /// Released buffers dropped because the pool was full or they were too large
+    pub discards: u64,
+    /// Buffers currently waiting in the pool
+    pub pooled_buffers: usize,
+    /// Total capacity of the pooled buffers
+    pub pooled_bytes: usize,
+}
+
+pub fn stats() -> Stats {
+    let (pooled_buffers, pooled_bytes) = POOL.get().map_or((0, 0), |pool| {
+        let buffers = pool.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        (buffers.len(), buffers.iter().map(Vec::capacity).sum())
+    });
+    Stats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        reuses: REUSES.load(Ordering::Relaxed),
+        discards: DISCARDS.load(Ordering::Relaxed),
+        pooled_buffers,
+        pooled_bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A private pool, so that tests of other modules using the global
+    // pool in parallel cannot take the buffer first
+    #[test]
+    fn test_take_reuses_released_buffers() {
+        let pool = BufferPool::new(2, 1 << 20);
+        assert!(pool.take(1000).is_none());
+
+        let mut buf = Vec::with_capacity(1000);
+        buf.extend_from_slice(&[1, 2, 3]);
+        let ptr = buf.as_ptr();
+        assert!(pool.give(buf));
+
+        // Too small for the request
+        assert!(pool.take(2000).is_none());
+        let again = pool.take(500).unwrap();
+        assert!(again.is_empty());
+        assert_eq!(again.as_ptr(), ptr);
+
+        // Oversized buffers and buffers beyond the pool size are not kept
+        assert!(!pool.give(Vec::with_capacity(2 << 20)));
+        assert!(pool.give(Vec::with_capacity(10)));
+        assert!(pool.give(Vec::with_capacity(10)));
+        assert!(!pool.give(Vec::with_capacity(10)));
+    }
+}
@@ -0,0 +1,360 @@
+//! Colour profile handling
+//!
+//! HEIF images describe their colour space either with an embedded ICC
+//! profile (`rICC`/`prof`) or with an `nclx` box listing primaries and a
+//! transfer curve. JPEG only understands ICC, so nclx descriptions are turned
+//! into an equivalent matrix/TRC profile with lcms2.
+//!
+//! For consumers that ignore embedded profiles, pixels can instead be
+//! transformed into sRGB with a chosen rendering intent.
+
+use crate::error::ConvertError;
+use lcms2::{CIExyY, CIExyYTRIPLE, Intent, PixelFormat, Profile, ToneCurve, Transform};
+use libheif_rs::{ColorPrimaries, ColorProfileNCLX, ColorProfileRaw, TransferCharacteristics};
+use std::str::FromStr;
+use tracing::warn;
+
+/// Number of entries in tabulated transfer curves (PQ / HLG)
+const CURVE_TABLE_SIZE: usize = 4096;
+
+/// Luminance in nits mapped to full scale when building PQ curves
+const PQ_REFERENCE_PEAK: f64 = 1000.0;
+
+/// Rendering intent used when mapping a wide-gamut source into sRGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntent {
+    /// Compress the whole gamut, preserving relationships between colours
+    Perceptual,
+    /// Keep in-gamut colours exact and clip the rest
+    RelativeColorimetric,
+    /// Favour vivid colours over accuracy
+    Saturation,
+    /// Like relative colorimetric, without white point adaptation
+    AbsoluteColorimetric,
+}
+
+impl FromStr for RenderingIntent {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "perceptual" => Ok(Self::Perceptual),
+            "relative" | "relative_colorimetric" => Ok(Self::RelativeColorimetric),
+            "saturation" => Ok(Self::Saturation),
+            "absolute" | "absolute_colorimetric" => Ok(Self::AbsoluteColorimetric),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid rendering intent: {}",
+                other
+            ))),
+        }
+    }
+}
+
+impl From<RenderingIntent> for Intent {
+    fn from(intent: RenderingIntent) -> Self {
+        match intent {
+            RenderingIntent::Perceptual => Intent::Perceptual,
+            RenderingIntent::RelativeColorimetric => Intent::RelativeColorimetric,
+            RenderingIntent::Saturation => Intent::Saturation,
+            RenderingIntent::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
+        }
+    }
+}
+
+/// Transform interleaved RGB(A) pixels from the source profile into sRGB in place
+///
+/// Alpha samples are left untouched.
+pub fn convert_to_srgb(
+    pixels: &mut [u8],
+    has_alpha: bool,
+    icc: &[u8],
+    intent: RenderingIntent,
+) -> Result<(), ConvertError> {
+    let source = Profile::new_icc(icc)
+        .map_err(|e| ConvertError::DecodeError(format!("Invalid colour profile: {}", e)))?;
+    let srgb = Profile::new_srgb();
+    let transform_error =
+        |e: lcms2::Error| ConvertError::Internal(format!("Colour transform failed: {}", e));
+
+    if has_alpha {
+        let transform = Transform::new(
+            &source,
+            PixelFormat::RGBA_8,
+            &srgb,
+            PixelFormat::RGBA_8,
+            intent.into(),
+        )
+        .map_err(transform_error)?;
+        transform.transform_in_place(bytemuck::cast_slice_mut::<u8, [u8; 4]>(pixels));
+    } else {
+        let transform = Transform::new(
+            &source,
+            PixelFormat::RGB_8,
+            &srgb,
+            PixelFormat::RGB_8,
+            intent.into(),
+        )
+        .map_err(transform_error)?;
+        transform.transform_in_place(bytemuck::cast_slice_mut::<u8, [u8; 3]>(pixels));
+    }
+    Ok(())
+}
+
+/// Parse a `#RRGGBB` (or `RRGGBB`) colour
+pub fn parse_hex_color(value: &str) -> Result<[u8; 3], ConvertError> {
+    let hex = value.trim().trim_start_matches('#');
+    let invalid = || ConvertError::ValidationError(format!("Invalid colour: {}", value));
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Composite RGBA pixels onto an opaque background, producing RGB
+pub fn flatten_alpha(rgba: &[u8], background: [u8; 3], premultiplied: bool) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+    for px in rgba.chunks_exact(4) {
+        let alpha = px[3] as u32;
+        for c in 0..3 {
+            let fg = px[c] as u32;
+            let bg = background[c] as u32 * (255 - alpha);
+            let value = if premultiplied {
+                fg * 255 + bg
+            } else {
+                fg * alpha + bg
+            };
+            rgb.push(((value + 127) / 255).min(255) as u8);
+        }
+    }
+    rgb
+}
+
+/// Convert premultiplied RGBA pixels to straight alpha in place
+pub fn unpremultiply_alpha(rgba: &mut [u8]) {
+    for px in rgba.chunks_exact_mut(4) {
+        let alpha = px[3] as u32;
+        if alpha == 0 || alpha == 255 {
+            continue;
+        }
+        for c in &mut px[..3] {
+            *c = ((*c as u32 * 255 + alpha / 2) / alpha).min(255) as u8;
+        }
+    }
+}
+
+/// Resolve the source colour profile to ICC bytes
+///
+/// An embedded ICC profile is used as-is. An nclx description is converted to
+/// a matching ICC profile, except plain sRGB, which is what readers assume
+/// for untagged images anyway.
+pub fn resolve_icc(
+    raw: Option<ColorProfileRaw>,
+    nclx: Option<ColorProfileNCLX>,
+) -> Option<Vec<u8>> {
+    if let Some(raw) = raw.filter(|raw| !raw.data.is_empty()) {
+        return Some(raw.data);
+    }
+
+    let nclx = nclx?;
+    if is_srgb(&nclx) {
+        return None;
+    }
+    let icc = transfer_curve(nclx.transfer_characteristics())
+        .and_then(|curve| icc_from_nclx(&nclx, &curve));
+    match icc {
+        Ok(icc) => Some(icc),
+        Err(e) => {
+            warn!(error = %e, "Failed to build ICC profile from nclx");
+            None
+        }
+    }
+}
+
+/// ICC profile for tone-mapped HDR pixels: the source primaries with the
+/// sRGB transfer curve, or `None` when that is plain sRGB
+pub fn sdr_icc(nclx: &ColorProfileNCLX) -> Option<Vec<u8>> {
+    if matches!(nclx.color_primaries(), ColorPrimaries::ITU_R_BT_709_5) {
+        return None;
+    }
+    match srgb_curve().and_then(|curve| icc_from_nclx(nclx, &curve)) {
+        Ok(icc) => Some(icc),
+        Err(e) => {
+            warn!(error = %e, "Failed to build ICC profile from nclx");
+            None
+        }
+    }
+}
+
+fn is_srgb(nclx: &ColorProfileNCLX) -> bool {
+    matches!(nclx.color_primaries(), ColorPrimaries::ITU_R_BT_709_5)
+        && matches!(
+            nclx.transfer_characteristics(),
+            TransferCharacteristics::IEC_61966_2_1
+        )
+}
+
+/// Build an ICC profile with the nclx primaries and the given tone curve
+fn icc_from_nclx(nclx: &ColorProfileNCLX, curve: &ToneCurve) -> lcms2::LCMSResult<Vec<u8>> {
+    let white = CIExyY {
+        x: nclx.color_primary_white_x() as f64,
+        y: nclx.color_primary_white_y() as f64,
+        Y: 1.0,
+    };
+    let primaries = CIExyYTRIPLE {
+        Red: CIExyY {
+            x: nclx.color_primary_red_x() as f64,
+            y: nclx.color_primary_red_y() as f64,
+            Y: 1.0,
+        },
+        Green: CIExyY {
+            x: nclx.color_primary_green_x() as f64,
+            y: nclx.color_primary_green_y() as f64,
+            Y: 1.0,
+        },
+        Blue: CIExyY {
+            x: nclx.color_primary_blue_x() as f64,
+            y: nclx.color_primary_blue_y() as f64,
+            Y: 1.0,
+        },
+    };
+
+    let profile = Profile::new_rgb(&white, &primaries, &[curve, curve, curve])?;
+    profile.icc()
+}
+
+/// Tone curve decoding the given transfer characteristics to linear light
+fn transfer_curve(transfer: TransferCharacteristics) -> lcms2::LCMSResult<ToneCurve> {
+    match transfer {
+        TransferCharacteristics::Linear => Ok(ToneCurve::new(1.0)),
+        TransferCharacteristics::ITU_R_BT_470_6_System_M => Ok(ToneCurve::new(2.2)),
+        TransferCharacteristics::ITU_R_BT_470_6_System_B_G => Ok(ToneCurve::new(2.8)),
+        // Inverse of the BT.709 OETF (shared by BT.601 and BT.2020)
+        TransferCharacteristics::ITU_R_BT_709_5
+        | TransferCharacteristics::ITU_R_BT_601_6
+        | TransferCharacteristics::ITU_R_BT_2020_2_10bit
+        | TransferCharacteristics::ITU_R_BT_2020_2_12bit => ToneCurve::new_parametric(
+            4,
+            &[1.0 / 0.45, 1.0 / 1.099, 0.099 / 1.099, 1.0 / 4.5, 0.081],
+        ),
+        TransferCharacteristics::ITU_R_BT_2100_0_PQ => {
+            Ok(tabulated_curve(|v| pq_eotf(v) / PQ_REFERENCE_PEAK))
+        }
+        TransferCharacteristics::ITU_R_BT_2100_0_HLG => Ok(tabulated_curve(hlg_inverse_oetf)),
+        // sRGB, and the assumed default for anything unspecified
+        _ => srgb_curve(),
+    }
+}
+
+fn srgb_curve() -> lcms2::LCMSResult<ToneCurve> {
+    ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+}
+
+fn tabulated_curve(f: impl Fn(f64) -> f64) -> ToneCurve {
+    let table: Vec<u16> = (0..CURVE_TABLE_SIZE)
+        .map(|i| {
+            let v = f(i as f64 / (CURVE_TABLE_SIZE - 1) as f64);
+            (v.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+        })
+        .collect();
+    ToneCurve::new_tabulated(&table)
+}
+
+/// SMPTE ST 2084 (PQ) EOTF: normalized signal to absolute luminance in nits
+pub fn pq_eotf(signal: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+
+    let p = signal.clamp(0.0, 1.0).powf(1.0 / M2);
+    let num = (p - C1).max(0.0);
+    let den = C2 - C3 * p;
+    10000.0 * (num / den).powf(1.0 / M1)
+}
+
+/// ARIB STD-B67 (HLG) inverse OETF: normalized signal to relative scene light
+pub fn hlg_inverse_oetf(signal: f64) -> f64 {
+    const A: f64 = 0.178_832_77;
+    const B: f64 = 0.284_668_92;
+    const C: f64 = 0.559_910_73;
+
+    let signal = signal.clamp(0.0, 1.0);
+    if signal <= 0.5 {
+        signal * signal / 3.0
+    } else {
+        (((signal - C) / A).exp() + B) / 12.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendering_intent_parse() {
+        assert_eq!(
+            "Perceptual".parse::<RenderingIntent>().unwrap(),
+            RenderingIntent::Perceptual
+        );
+        assert_eq!(
+            "relative".parse::<RenderingIntent>().unwrap(),
+            RenderingIntent::RelativeColorimetric
+        );
+        assert!("vivid".parse::<RenderingIntent>().is_err());
+    }
+
+    #[test]
+    fn test_srgb_to_srgb_is_identity() {
+        let icc = Profile::new_srgb().icc().unwrap();
+        let mut rgb = vec![0, 0, 0, 255, 128, 0, 12, 200, 255];
+        let original = rgb.clone();
+        convert_to_srgb(&mut rgb, false, &icc, RenderingIntent::RelativeColorimetric).unwrap();
+        for (a, b) in rgb.iter().zip(&original) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(parse_hex_color("#ffffff").unwrap(), [255, 255, 255]);
+        assert_eq!(parse_hex_color("1a2B3c").unwrap(), [0x1a, 0x2b, 0x3c]);
+        assert!(parse_hex_color("#fff").is_err());
+        assert!(parse_hex_color("#gggggg").is_err());
+    }
+
+    #[test]
+    fn test_flatten_alpha() {
+        let rgba = [255, 0, 0, 255, 255, 0, 0, 0, 200, 0, 0, 128];
+        let white = [255, 255, 255];
+        assert_eq!(
+            flatten_alpha(&rgba, white, false),
+            vec![255, 0, 0, 255, 255, 255, 227, 127, 127]
+        );
+        // Premultiplied: colour already scaled by alpha
+        assert_eq!(
+            flatten_alpha(&[100, 0, 0, 128], [0, 0, 0], true),
+            vec![100, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_unpremultiply_alpha() {
+        let mut rgba = vec![100, 50, 0, 128, 10, 20, 30, 255, 5, 5, 5, 0];
+        unpremultiply_alpha(&mut rgba);
+        assert_eq!(rgba, vec![199, 100, 0, 128, 10, 20, 30, 255, 5, 5, 5, 0]);
+    }
+
+    #[test]
+    fn test_transfer_functions() {
+        assert!((pq_eotf(1.0) - 10000.0).abs() < 1e-6);
+        assert!(pq_eotf(0.0).abs() < 1e-9);
+        // ~100 nits sits around 51% PQ signal
+        assert!((pq_eotf(0.508) - 100.0).abs() < 2.0);
+
+        assert!((hlg_inverse_oetf(0.5) - 1.0 / 12.0).abs() < 1e-9);
+        assert!((hlg_inverse_oetf(1.0) - 1.0).abs() < 1e-6);
+    }
+}
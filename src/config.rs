@@ -1,10 +1,14 @@
 //! Configuration management
 //!
-//! Includes smart CPU detection for optimal resource utilization.
+//! Worker count, queue size and memory budget default to what the cgroup
+//! CPU quota and memory limit allow, falling back to the host's cores and
+//! memory outside a container.
 
 use dotenvy::dotenv;
 use serde::Deserialize;
 use std::env;
+use std::fs;
+use tracing::info;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -12,6 +16,12 @@ pub struct Config {
     pub max_file_size: usize,
     /// Maximum image resolution (width or height)
     pub max_resolution: u32,
+    /// Maximum image area in megapixels
+    pub max_megapixels: u32,
+    /// Maximum estimated memory for one conversion, in bytes
+    pub max_job_memory: usize,
+    /// Maximum children per HEIF box accepted by libheif
+    pub max_children: u32,
     /// Default JPEG quality (1-100)
     pub default_quality: u8,
     /// Minimum allowed quality
@@ -22,64 +32,253 @@ pub struct Config {
     pub worker_count: usize,
     /// Maximum pending jobs in queue
     pub queue_size: usize,
+    /// Estimated memory all running conversions may hold at once, in bytes
+    pub memory_budget: usize,
     /// Server port
     pub server_port: u16,
     /// Request timeout in seconds
     pub request_timeout_secs: u64,
     /// Directory to store uploaded files for audit
+    // Read only by the audit logging, which is temporarily disabled
+    #[allow(dead_code)]
     pub upload_dir: String,
+    /// Encode JPEGs from coded YCbCr when no pixel operation is requested
+    pub ycbcr_fast_path: bool,
+    /// Number of released pixel/encoder buffers kept for reuse
+    pub buffer_pool_size: usize,
+    /// Largest buffer in bytes the pool keeps
+    pub buffer_pool_max_bytes: usize,
 }
 
-/// Smart CPU detection for optimal worker configuration
+/// A typical 12 MP photo, used to size the pool when nothing else is known
+const TYPICAL_JOB_MEMORY: usize = 4032 * 3024 * 3 * 4;
+/// A 12 MP HEIC upload together with its encoded result
+const TYPICAL_UPLOAD_BYTES: usize = 8 * 1024 * 1024;
+/// Budget when neither a cgroup limit nor the host memory can be read
+const FALLBACK_MEMORY_BUDGET: usize = 4 * 1024 * 1024 * 1024;
+
+/// CPU and memory available to this process
 ///
-/// Strategy:
-/// - Detects available parallelism (logical cores)
-/// - For CPU-bound image processing, using all logical cores is beneficial
-/// - Reserves 1 core for I/O tasks if we have more than 4 cores
-/// - Ensures minimum of 2 workers and maximum based on available parallelism
-fn detect_optimal_workers() -> usize {
-    let logical_cores = std::thread::available_parallelism()
-        .map(|p| p.get())
-        .unwrap_or(4);
-
-    // Strategy for CPU-bound image conversion:
-    // - Use most cores for conversion work
-    // - Reserve 1 for async I/O if we have plenty (>4)
-    // - Minimum 2 workers, maximum = logical cores
-    let workers = if logical_cores > 4 {
-        logical_cores - 1 // Reserve one for I/O
-    } else {
-        logical_cores // Use all cores on smaller systems
-    };
-
-    workers.max(2) // Ensure at least 2 workers
+/// Inside a container the host's core count and memory say little about
+/// what we may use; the cgroup CPU quota and memory limit take precedence.
+#[derive(Debug, Clone, Copy, Default)]
+struct Resources {
+    /// Logical cores we may be scheduled on
+    logical_cores: usize,
+    /// CPUs granted by a cgroup quota, possibly fractional
+    cpu_quota: Option<f64>,
+    /// cgroup memory limit in bytes
+    memory_limit: Option<usize>,
+    /// Physical memory of the host in bytes
+    host_memory: Option<usize>,
+}
+
+impl Resources {
+    fn detect() -> Self {
+        Self {
+            logical_cores: std::thread::available_parallelism()
+                .map(|p| p.get())
+                .unwrap_or(4),
+            cpu_quota: cgroup_cpu_quota(),
+            memory_limit: cgroup_memory_limit(),
+            host_memory: host_memory(),
+        }
+    }
+
+    /// CPUs workers may keep busy
+    ///
+    /// A fractional quota is rounded down: a worker per partial CPU would
+    /// only get throttled.
+    fn cpus(&self) -> usize {
+        match self.cpu_quota {
+            Some(quota) => (quota.floor() as usize).clamp(1, self.logical_cores.max(1)),
+            None => self.logical_cores.max(1),
+        }
+    }
+
+    /// Memory we may use: the cgroup limit, or the host's memory
+    fn memory(&self) -> Option<usize> {
+        match (self.memory_limit, self.host_memory) {
+            (Some(limit), Some(host)) => Some(limit.min(host)),
+            (limit, host) => limit.or(host),
+        }
+    }
+
+    /// Memory budget shared by running conversions
+    ///
+    /// Three quarters of our memory, leaving the rest for queued uploads,
+    /// encoded results and the runtime.
+    fn memory_budget(&self) -> usize {
+        self.memory()
+            .map(|memory| memory / 4 * 3)
+            .unwrap_or(FALLBACK_MEMORY_BUDGET)
+    }
 }
 
-/// Calculate optimal queue size based on worker count
+/// One worker per usable CPU, but no more than `memory_budget` can keep
+/// busy with typical photos
 ///
-/// Strategy:
-/// - Queue should be large enough to absorb burst traffic
-/// - But not so large that we waste memory on pending requests
-/// - Formula: workers * 4 for burst absorption, minimum 100
-fn calculate_optimal_queue_size(worker_count: usize) -> usize {
-    let base_queue = worker_count * 4;
-    base_queue.max(100) // Minimum 100 for small worker counts
+/// Async I/O is light next to decoding, so no core is held back for it.
+fn derive_workers(cpus: usize, memory_budget: usize) -> usize {
+    cpus.min(memory_budget / TYPICAL_JOB_MEMORY).max(1)
+}
+
+/// Four pending jobs per worker to absorb bursts, at least 100, but no
+/// more typical uploads than fit in the memory left outside the budget
+fn derive_queue_size(workers: usize, memory: Option<usize>, memory_budget: usize) -> usize {
+    let burst = (workers * 4).max(100);
+    match memory {
+        Some(memory) => {
+            let headroom = memory.saturating_sub(memory_budget) / TYPICAL_UPLOAD_BYTES;
+            burst.min(headroom).max(workers)
+        }
+        None => burst,
+    }
+}
+
+/// CPUs granted by our cgroup's quota, from cgroup v2 or else v1
+fn cgroup_cpu_quota() -> Option<f64> {
+    match fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        Ok(v2) => parse_cpu_max(&v2),
+        Err(_) => parse_cfs_quota(
+            &fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?,
+            &fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?,
+        ),
+    }
+}
+
+/// Parse cgroup v2 `cpu.max`: `<quota> <period>`, quota `max` when unlimited
+fn parse_cpu_max(value: &str) -> Option<f64> {
+    let mut fields = value.split_whitespace();
+    let quota = fields.next()?;
+    let period = fields.next().unwrap_or("100000");
+    parse_cfs_quota(quota, period)
+}
+
+/// Parse cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us`; the quota
+/// is -1 when unlimited
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota: f64 = quota.trim().parse().ok()?;
+    let period: f64 = period.trim().parse().ok()?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Memory limit of our cgroup, from cgroup v2 or else v1
+fn cgroup_memory_limit() -> Option<usize> {
+    match fs::read_to_string("/sys/fs/cgroup/memory.max") {
+        Ok(v2) => parse_memory_limit(&v2),
+        Err(_) => parse_memory_limit(
+            &fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes").ok()?,
+        ),
+    }
+}
+
+/// Parse a cgroup memory limit; v2 writes `max` and v1 a value near
+/// `i64::MAX` when there is none
+fn parse_memory_limit(value: &str) -> Option<usize> {
+    let limit: u64 = value.trim().parse().ok()?;
+    (limit < 1 << 60).then_some(limit as usize)
+}
+
+/// Physical memory from `/proc/meminfo`
+fn host_memory() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kib: usize = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+fn mb(bytes: usize) -> usize {
+    bytes / 1024 / 1024
 }
 
 impl Config {
     pub fn from_env() -> Self {
         dotenv().ok(); // Load .env if present
 
-        // Detect optimal workers first (used for queue calculation if not overridden)
-        let optimal_workers = detect_optimal_workers();
+        // Size the pool from the CPU quota and memory limit unless set explicitly
+        let resources = Resources::detect();
+        info!(
+            logical_cores = resources.logical_cores,
+            cpu_quota = ?resources.cpu_quota,
+            memory_limit_mb = ?resources.memory_limit.map(mb),
+            host_memory_mb = ?resources.host_memory.map(mb),
+            "Detected resources"
+        );
 
-        let worker_count = env::var("WORKER_COUNT")
+        let memory_budget = match env::var("MEMORY_BUDGET")
             .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(optimal_workers);
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(budget) => {
+                info!(
+                    memory_budget_mb = mb(budget),
+                    "Memory budget set by MEMORY_BUDGET"
+                );
+                budget
+            }
+            None => {
+                let budget = resources.memory_budget();
+                match resources.memory() {
+                    Some(memory) => info!(
+                        memory_budget_mb = mb(budget),
+                        "Memory budget is 3/4 of {}MB available memory",
+                        mb(memory)
+                    ),
+                    None => info!(
+                        memory_budget_mb = mb(budget),
+                        "Memory budget falls back to 4GB, available memory unknown"
+                    ),
+                }
+                budget
+            }
+        };
 
-        // Queue size based on worker count for optimal throughput
-        let default_queue = calculate_optimal_queue_size(worker_count);
+        let worker_count = match env::var("WORKER_COUNT")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(workers) => {
+                info!(workers, "Worker count set by WORKER_COUNT");
+                workers
+            }
+            None => {
+                let cpus = resources.cpus();
+                let workers = derive_workers(cpus, memory_budget);
+                info!(
+                    workers,
+                    "Worker count from {} usable CPUs ({}), capped at {} typical 12 MP jobs in the memory budget",
+                    cpus,
+                    if resources.cpu_quota.is_some() { "cgroup quota" } else { "logical cores" },
+                    memory_budget / TYPICAL_JOB_MEMORY
+                );
+                workers
+            }
+        };
+
+        let queue_size = match env::var("QUEUE_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(queue_size) => {
+                info!(queue_size, "Queue size set by QUEUE_SIZE");
+                queue_size
+            }
+            None => {
+                let queue_size = derive_queue_size(worker_count, resources.memory(), memory_budget);
+                info!(
+                    queue_size,
+                    "Queue size is 4 per worker (at least 100), capped by uploads fitting outside the memory budget"
+                );
+                queue_size
+            }
+        };
 
         Self {
             max_file_size: env::var("MAX_FILE_SIZE")
@@ -92,6 +291,21 @@ impl Config {
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(16384),
 
+            max_megapixels: env::var("MAX_MEGAPIXELS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+
+            max_job_memory: env::var("MAX_JOB_MEMORY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2 * 1024 * 1024 * 1024), // 2GB, a 100 MP 10-bit image
+
+            max_children: env::var("MAX_CHILDREN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100), // libheif's own default
+
             default_quality: env::var("DEFAULT_QUALITY")
                 .ok()
                 .and_then(|v| v.parse().ok())
@@ -109,10 +323,9 @@ impl Config {
 
             worker_count,
 
-            queue_size: env::var("QUEUE_SIZE")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(default_queue),
+            queue_size,
+
+            memory_budget,
 
             server_port: env::var("SERVER_PORT")
                 .ok()
@@ -125,9 +338,91 @@ impl Config {
                 .unwrap_or(30),
 
             upload_dir: env::var("UPLOAD_DIR").unwrap_or_else(|_| "uploads".to_string()),
+
+            ycbcr_fast_path: env::var("YCBCR_FAST_PATH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true),
+
+            // Each busy worker holds a pixel buffer and an encoder buffer
+            buffer_pool_size: env::var("BUFFER_POOL_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(worker_count * 2),
+
+            buffer_pool_max_bytes: env::var("BUFFER_POOL_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024 * 1024), // 64MB, a 12MP RGBA frame
         }
     }
 }
 
 // Defaults for backward compatibility/testing if needed,
 // though direct usage should prefer the struct.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: usize = 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_parse_cpu_quota() {
+        assert_eq!(parse_cpu_max("200000 100000\n"), Some(2.0));
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cfs_quota("50000\n", "100000\n"), Some(0.5));
+        assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+    }
+
+    #[test]
+    fn test_derive_sizing() {
+        // 1.5 CPUs and 2GB in a 16-core node
+        let resources = Resources {
+            logical_cores: 16,
+            cpu_quota: Some(1.5),
+            memory_limit: Some(2 * GB),
+            host_memory: Some(64 * GB),
+        };
+        assert_eq!(resources.cpus(), 1);
+        assert_eq!(resources.memory_budget(), 3 * GB / 2);
+        assert_eq!(
+            derive_workers(resources.cpus(), resources.memory_budget()),
+            1
+        );
+        // 512MB outside the budget holds 64 uploads
+        assert_eq!(
+            derive_queue_size(1, resources.memory(), resources.memory_budget()),
+            64
+        );
+
+        // 8 CPUs but only 512MB: memory limits the workers
+        assert_eq!(derive_workers(8, 384 * 1024 * 1024), 2);
+        assert_eq!(
+            derive_queue_size(2, Some(512 * 1024 * 1024), 384 * 1024 * 1024),
+            16
+        );
+
+        // Unconstrained host
+        let host = Resources {
+            logical_cores: 32,
+            host_memory: Some(128 * GB),
+            ..Resources::default()
+        };
+        assert_eq!(derive_workers(host.cpus(), host.memory_budget()), 32);
+        assert_eq!(
+            derive_queue_size(32, host.memory(), host.memory_budget()),
+            128
+        );
+        assert_eq!(derive_queue_size(4, None, FALLBACK_MEMORY_BUDGET), 100);
+    }
+
+    #[test]
+    fn test_parse_memory_limit() {
+        assert_eq!(parse_memory_limit("2147483648\n"), Some(2 << 30));
+        // v2 and v1 spellings of "no limit"
+        assert_eq!(parse_memory_limit("max\n"), None);
+        assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+    }
+}
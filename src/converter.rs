@@ -1,16 +1,48 @@
-//! Core HEIC to JPEG conversion engine
+//! Core HEIC conversion engine
 //!
 //! Optimized with thread-local caching for maximum performance.
 
+use crate::archive::{Manifest, ManifestImage, ManifestLayer, ZipArchive};
+use crate::avif;
+use crate::buffers;
+use crate::color::{self, RenderingIntent};
+use crate::config::Config;
+use crate::crop::{self, Crop, Gravity};
 use crate::error::ConvertError;
-use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use crate::isobmff::{self, HeifFormat};
+use crate::layers::{self, Layer, LayerFormat};
+use crate::limits::{self, Limits};
+use crate::metadata;
+use crate::raster::{self, RasterFormat};
+use crate::resize::{self, FitMode};
+use crate::tonemap::{HdrTransfer, ToneMapOperator, ToneMapper};
+use crate::ycbcr;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use libheif_rs::{
+    color_profile_types, Channel, ColorProfileNCLX, ColorProfileRaw, ColorSpace, CompressionFormat,
+    DecodingOptions, EncoderParameterValue, EncoderQuality, HeifContext, ImageHandle, ItemId,
+    LibHeif, RgbChroma,
+};
 use std::cell::RefCell;
-use turbojpeg::{Compressor, Image, PixelFormat};
+use std::str::FromStr;
+use turbojpeg::{
+    Compressor, Image, OutputBuf, PixelFormat, Subsamp, Transform, TransformOp, YuvImage,
+};
+
+/// Quality from which `subsampling=auto` keeps full chroma resolution
+const AUTO_FULL_CHROMA_QUALITY: u8 = 90;
+
+/// Scale factor applied per step when downscaling to reach `max_bytes`
+const DOWNSCALE_STEP: f64 = 0.75;
+
+/// Smallest longer side the `max_bytes` downscale fallback goes down to
+const MIN_DOWNSCALE_SIDE: u32 = 64;
 
 // Thread-local LibHeif instance - avoid initialization overhead per request
 thread_local! {
     static LIB_HEIF: LibHeif = LibHeif::new();
-    static COMPRESSOR: RefCell<Option<Compressor>> = RefCell::new(None);
+    static COMPRESSOR: RefCell<Option<Compressor>> = const { RefCell::new(None) };
 }
 
 /// Get or create thread-local compressor
@@ -27,146 +59,1666 @@ where
     })
 }
 
-/// Decode HEIC bytes to RGB image buffer
-fn decode_heic(data: &[u8], max_resolution: u32) -> Result<(Vec<u8>, u32, u32), ConvertError> {
+/// Storage of decoded pixels
+enum Pixels {
+    /// Rows still inside the decoded libheif image, `stride` bytes apart
+    Heif {
+        image: libheif_rs::Image,
+        stride: usize,
+    },
+    /// Tightly packed rows in a pooled buffer
+    Packed(Vec<u8>),
+}
+
+/// Decoded pixels plus the source metadata carried over to the encoder
+struct DecodedImage {
+    /// Interleaved RGB, or RGBA when `has_alpha` is set
+    pixels: Pixels,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    /// Colour samples are already multiplied by alpha
+    premultiplied_alpha: bool,
+    /// `pixels` holds coded YCbCr planes in turbojpeg's planar layout with
+    /// this subsampling, rather than RGB
+    ycbcr: Option<Subsamp>,
+    /// Bare TIFF structure of the Exif block, if present
+    exif: Option<Vec<u8>>,
+    /// Source colour profile as ICC, `None` for sRGB
+    icc: Option<Vec<u8>>,
+    /// Exif Orientation of the decoded pixels (1 once transformations are applied)
+    orientation: u16,
+    /// Depth and auxiliary layers, decoded only when requested
+    layers: Vec<Layer>,
+}
+
+impl DecodedImage {
+    /// Bytes in a row of interleaved pixels, without padding
+    fn row_len(&self) -> usize {
+        self.width as usize * if self.has_alpha { 4 } else { 3 }
+    }
+
+    /// Pixel data and the distance between rows, without copying
+    fn rows(&self) -> (&[u8], usize) {
+        match &self.pixels {
+            Pixels::Heif { image, stride } => {
+                let data = image
+                    .planes()
+                    .interleaved
+                    .map_or(&[][..], |plane| plane.data);
+                (data, *stride)
+            }
+            Pixels::Packed(buf) => (buf, self.row_len()),
+        }
+    }
+
+    /// Tightly packed pixels, copied out of the libheif image on first use
+    fn packed(&mut self) -> &mut Vec<u8> {
+        if let Pixels::Heif { .. } = self.pixels {
+            let row_len = self.row_len();
+            let (data, stride) = self.rows();
+            let mut buf = buffers::take(row_len * self.height as usize);
+            for row in data.chunks(stride).take(self.height as usize) {
+                buf.extend_from_slice(&row[..row_len]);
+            }
+            self.pixels = Pixels::Packed(buf);
+        }
+        match &mut self.pixels {
+            Pixels::Packed(buf) => buf,
+            Pixels::Heif { .. } => unreachable!("pixels were just packed"),
+        }
+    }
+
+    /// Move the packed pixels out, e.g. to replace them with a resized copy
+    fn take_packed(&mut self) -> Vec<u8> {
+        std::mem::take(self.packed())
+    }
+
+    /// Return the pixel buffer to the pool
+    fn release(self) {
+        if let Pixels::Packed(buf) = self.pixels {
+            buffers::give(buf);
+        }
+    }
+}
+
+/// Pick the smallest embedded thumbnail whose longer side still covers `size`
+fn select_thumbnail(primary: &ImageHandle, size: u32) -> Option<ImageHandle> {
+    let mut ids: Vec<ItemId> = vec![0; primary.number_of_thumbnails()];
+    let count = primary.thumbnail_ids(&mut ids);
+    ids.truncate(count);
+
+    ids.into_iter()
+        .filter_map(|id| primary.thumbnail(id).ok())
+        .filter(|thumb| thumb.width().max(thumb.height()) >= size)
+        .min_by_key(|thumb| thumb.width() as u64 * thumb.height() as u64)
+}
+
+/// Detected input format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Heif(HeifFormat),
+    Raster(RasterFormat),
+}
+
+impl InputFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Heif(format) => format.as_str(),
+            Self::Raster(format) => format.as_str(),
+        }
+    }
+}
+
+/// Identify the input from its signature or `ftyp` brands
+pub fn sniff_input(data: &[u8]) -> Result<InputFormat, ConvertError> {
+    match RasterFormat::sniff(data) {
+        Some(format) => Ok(InputFormat::Raster(format)),
+        None => sniff_heif(data).map(InputFormat::Heif),
+    }
+}
+
+/// Identify a HEIF-family input from its `ftyp` brands before handing it
+/// to libheif
+fn sniff_heif(data: &[u8]) -> Result<HeifFormat, ConvertError> {
+    let ftyp = isobmff::file_type(data).ok_or_else(|| {
+        ConvertError::DecodeError(
+            "Unrecognised input (expected HEIC, HEIF, AVIF, JPEG, PNG or WebP)".to_string(),
+        )
+    })?;
+    ftyp.heif_format()
+        .ok_or_else(|| ConvertError::UnsupportedBrand(isobmff::fourcc(&ftyp.major_brand)))
+}
+
+/// Peak memory a conversion of `data` needs, from its headers alone
+///
+/// Applies the same limit checks as decoding and resizing, so inputs that
+/// would be rejected fail before they are queued. Images are decoded one at
+/// a time, so the largest selected image, with its layers, sets the peak. A
+/// ZIP archive keeps every encoded file until it is finished; those are
+/// counted at their uncompressed 8-bit size. A resize target is laid out on
+/// the header size; the exact check before resampling also covers a
+/// raster's Exif rotation.
+///
+/// Parses the file with libheif, so call it off the async runtime.
+pub fn estimate_memory(data: &[u8], options: &ConvertOptions) -> Result<usize, ConvertError> {
+    let images = match sniff_input(data)? {
+        InputFormat::Raster(format) => {
+            let (width, height, bit_depth, has_alpha) = raster::read_header(data, format)?;
+            vec![estimate_image(
+                options,
+                (width, height),
+                bit_depth,
+                has_alpha,
+            )?]
+        }
+        InputFormat::Heif(_) => {
+            let ctx = limits::read_context(data, &options.limits)?;
+            let ids = ctx.image_ids();
+            let handles = match options.images {
+                // Previews may come from an embedded thumbnail, as in decode_heic
+                ImageSelection::Primary => vec![ctx.primary_image_handle().map(|primary| {
+                    options
+                        .thumbnail
+                        .and_then(|size| select_thumbnail(&primary, size))
+                        .unwrap_or(primary)
+                })],
+                // An index out of range is reported by the conversion itself
+                ImageSelection::Index(index) => ids
+                    .get(index)
+                    .map(|&id| ctx.image_handle(id))
+                    .into_iter()
+                    .collect(),
+                ImageSelection::All => ids.iter().map(|&id| ctx.image_handle(id)).collect(),
+            };
+            handles
+                .into_iter()
+                .map(|handle| {
+                    let handle = handle.map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+                    let mut image = estimate_image(
+                        options,
+                        (handle.width(), handle.height()),
+                        handle.luma_bits_per_pixel(),
+                        handle.has_alpha_channel(),
+                    )?;
+                    if options.layers.is_some() {
+                        let (memory, encoded) = layers::estimate_memory(&handle, &options.limits)?;
+                        image.memory += memory;
+                        image.encoded += encoded;
+                    }
+                    Ok(image)
+                })
+                .collect::<Result<Vec<_>, ConvertError>>()?
+        }
+    };
+
+    let mut peak = 0;
+    for image in &images {
+        if image.memory > options.limits.max_job_memory {
+            return Err(ConvertError::MemoryLimitExceeded {
+                required: image.memory,
+                max: options.limits.max_job_memory,
+            });
+        }
+        peak = peak.max(image.memory);
+    }
+    if options.images == ImageSelection::All || options.layers.is_some() {
+        peak += images.iter().map(|image| image.encoded).sum::<usize>();
+    }
+    Ok(peak)
+}
+
+/// Memory estimate for converting one image
+struct ImageEstimate {
+    /// Peak while the image is decoded, processed and encoded
+    memory: usize,
+    /// Upper bound on the encoded output kept in a ZIP archive
+    encoded: usize,
+}
+
+fn estimate_image(
+    options: &ConvertOptions,
+    size: (u32, u32),
+    bit_depth: u8,
+    has_alpha: bool,
+) -> Result<ImageEstimate, ConvertError> {
+    let source = options.limits.check(size.0, size.1, bit_depth, has_alpha)?;
+
+    // A crop that does not fit is reported by the conversion itself
+    let size = options
+        .crop
+        .as_ref()
+        .and_then(|request| request.resolve(size, options.gravity).ok())
+        .map_or(size, |rect| (rect.width, rect.height));
+    let target = (options.width, options.height);
+    let resized = resize::check(size, target, options.fit, has_alpha, &options.limits)?;
+
+    let (width, height) = resize::output_size(size, target, options.fit);
+    let channels = if has_alpha && options.format.supports_alpha() {
+        4
+    } else {
+        3
+    };
+    Ok(ImageEstimate {
+        memory: source + resized,
+        encoded: width as usize * height as usize * channels,
+    })
+}
+
+/// Decode any supported input, handing each requested image to `f` in turn
+///
+/// `f` receives the image's position among the top-level images (0 for the
+/// primary image). A JPEG, PNG or WebP input is a single image at index 0.
+/// `quality` is the requested encoding quality, which picks the chroma
+/// subsampling the YCbCr fast path has to match.
+fn decode_input<F>(
+    data: &[u8],
+    quality: u8,
+    options: &ConvertOptions,
+    mut f: F,
+) -> Result<(), ConvertError>
+where
+    F: FnMut(usize, DecodedImage) -> Result<(), ConvertError>,
+{
+    match sniff_input(data)? {
+        InputFormat::Heif(_) => decode_heic(data, quality, options, f),
+        InputFormat::Raster(format) => {
+            if let ImageSelection::Index(index @ 1..) = options.images {
+                return Err(ConvertError::ValidationError(format!(
+                    "Image index {} out of range (file has 1 images)",
+                    index
+                )));
+            }
+            f(0, decode_raster(data, format, options)?)
+        }
+    }
+}
+
+/// Decode HEIC, HEIF or AVIF bytes, handing each requested image to `f` in turn
+///
+/// Images are decoded one at a time so a multi-image file never holds more
+/// than one full-size pixel buffer.
+fn decode_heic<F>(
+    data: &[u8],
+    quality: u8,
+    options: &ConvertOptions,
+    mut f: F,
+) -> Result<(), ConvertError>
+where
+    F: FnMut(usize, DecodedImage) -> Result<(), ConvertError>,
+{
     // Use thread-local LibHeif instance
     LIB_HEIF.with(|lib_heif| {
-        // Create HEIF context from bytes
-        let ctx = HeifContext::read_from_bytes(data)
-            .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+        // Create HEIF context from bytes, within the configured limits
+        let ctx = limits::read_context(data, &options.limits)?;
 
-        // Get primary image handle
-        let handle = ctx
-            .primary_image_handle()
-            .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
-
-        let width = handle.width();
-        let height = handle.height();
-
-        // Check resolution limits
-        if width > max_resolution || height > max_resolution {
-            return Err(ConvertError::ImageTooLarge {
-                width,
-                height,
-                max: max_resolution,
-            });
+        if options.images == ImageSelection::Primary {
+            // Get primary image handle
+            let primary = ctx
+                .primary_image_handle()
+                .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+
+            // Previews decode an embedded thumbnail instead when one is big enough
+            let thumbnail = options
+                .thumbnail
+                .and_then(|size| select_thumbnail(&primary, size));
+            let handle = thumbnail.as_ref().unwrap_or(&primary);
+
+            return f(
+                0,
+                decode_handle(lib_heif, data, handle, &primary, quality, options)?,
+            );
         }
 
-        // Decode to RGB using thread-local LibHeif instance
-        let image = lib_heif
-            .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
-            .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+        let ids = ctx.image_ids();
+        let selected: Vec<(usize, ItemId)> = match options.images {
+            ImageSelection::Index(index) => {
+                let id = ids.get(index).copied().ok_or_else(|| {
+                    ConvertError::ValidationError(format!(
+                        "Image index {} out of range (file has {} images)",
+                        index,
+                        ids.len()
+                    ))
+                })?;
+                vec![(index, id)]
+            }
+            _ => ids.into_iter().enumerate().collect(),
+        };
 
-        let planes = image.planes();
+        for (index, id) in selected {
+            let handle = ctx
+                .image_handle(id)
+                .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+            f(
+                index,
+                decode_handle(lib_heif, data, &handle, &handle, quality, options)?,
+            )?;
+        }
+        Ok(())
+    })
+}
 
-        let interleaved = planes.interleaved.ok_or_else(|| {
-            ConvertError::DecodeError("Failed to get interleaved RGB data".to_string())
-        })?;
+/// Decode one image handle to an RGB(A) buffer, or to YCbCr planes when
+/// the request allows the fast path
+///
+/// Exif and fallback colour information come from `metadata_source`, which
+/// differs from `handle` when decoding a thumbnail.
+fn decode_handle(
+    lib_heif: &LibHeif,
+    data: &[u8],
+    handle: &ImageHandle,
+    metadata_source: &ImageHandle,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Result<DecodedImage, ConvertError> {
+    options.limits.check(
+        handle.width(),
+        handle.height(),
+        handle.luma_bits_per_pixel(),
+        handle.has_alpha_channel(),
+    )?;
+
+    // libheif applies irot/imir unless told otherwise; in preserve mode
+    // the coded pixels are kept and the rotation moves to Exif
+    let (decoding_options, orientation) = match options.orientation {
+        OrientationMode::Apply => (None, 1),
+        OrientationMode::Preserve => {
+            let mut decoding_options = DecodingOptions::new().ok_or_else(|| {
+                ConvertError::Internal("Failed to allocate decoding options".to_string())
+            })?;
+            decoding_options.set_ignore_transformations(true);
+            (
+                Some(decoding_options),
+                isobmff::item_exif_orientation(data, handle.item_id()),
+            )
+        }
+    };
+
+    let mut decoded = match ycbcr_fast_path(handle, metadata_source, quality, options) {
+        Some((colorspace, subsamp)) => decode_ycbcr(
+            lib_heif,
+            handle,
+            metadata_source,
+            colorspace,
+            subsamp,
+            decoding_options,
+        )?,
+        None => decode_rgb(lib_heif, handle, metadata_source, decoding_options, options)?,
+    };
+
+    decoded.exif = metadata::read_exif(metadata_source);
+    decoded.orientation = orientation;
+    if options.layers.is_some() {
+        decoded.layers = layers::decode_layers(lib_heif, handle, &options.limits)?;
+    }
+    Ok(decoded)
+}
+
+/// Decode a JPEG, PNG or WebP input
+///
+/// Rasters carry their rotation in Exif only, so it is applied here in
+/// `apply` mode; in `preserve` mode the stored pixels are kept as they are.
+fn decode_raster(
+    data: &[u8],
+    format: RasterFormat,
+    options: &ConvertOptions,
+) -> Result<DecodedImage, ConvertError> {
+    let image = raster::decode(data, format, &options.limits)?;
+    let channels = if image.has_alpha { 4 } else { 3 };
+
+    let (pixels, width, height, orientation) = match options.orientation {
+        OrientationMode::Apply => {
+            let (pixels, width, height) = raster::apply_orientation(
+                image.pixels,
+                channels,
+                (image.width, image.height),
+                image.orientation,
+            );
+            (pixels, width, height, 1)
+        }
+        OrientationMode::Preserve => (image.pixels, image.width, image.height, image.orientation),
+    };
+
+    Ok(DecodedImage {
+        pixels: Pixels::Packed(pixels),
+        width,
+        height,
+        has_alpha: image.has_alpha,
+        premultiplied_alpha: false,
+        ycbcr: None,
+        exif: image.exif,
+        icc: image.icc,
+        orientation,
+        layers: Vec::new(),
+    })
+}
+
+/// Source colour profiles, preferring the handle over the decoded image and
+/// the metadata source
+fn source_profiles(
+    handle: &ImageHandle,
+    image: &libheif_rs::Image,
+    metadata_source: &ImageHandle,
+) -> (Option<ColorProfileRaw>, Option<ColorProfileNCLX>) {
+    let raw = handle
+        .color_profile_raw()
+        .or_else(|| image.color_profile_raw())
+        .or_else(|| metadata_source.color_profile_raw());
+    let nclx = handle
+        .color_profile_nclx()
+        .or_else(|| image.color_profile_nclx())
+        .or_else(|| metadata_source.color_profile_nclx());
+    (raw, nclx)
+}
+
+/// Decode to interleaved RGB(A), reducing high bit depth input to 8 bits
+fn decode_rgb(
+    lib_heif: &LibHeif,
+    handle: &ImageHandle,
+    metadata_source: &ImageHandle,
+    decoding_options: Option<DecodingOptions>,
+    options: &ConvertOptions,
+) -> Result<DecodedImage, ConvertError> {
+    // Decode using thread-local LibHeif instance, keeping alpha if present
+    // and the full precision of high bit depth images
+    let has_alpha = handle.has_alpha_channel();
+    let high_bit_depth = handle.luma_bits_per_pixel() > 8;
+    let chroma = match (has_alpha, high_bit_depth) {
+        (false, false) => RgbChroma::Rgb,
+        (true, false) => RgbChroma::Rgba,
+        (false, true) => RgbChroma::HdrRgbLe,
+        (true, true) => RgbChroma::HdrRgbaLe,
+    };
+    let channels = if has_alpha { 4 } else { 3 };
+    let image = lib_heif
+        .decode(handle, ColorSpace::Rgb(chroma), decoding_options)
+        .map_err(limits::heif_error)?;
+
+    let (raw_profile, nclx) = source_profiles(handle, &image, metadata_source);
+
+    let interleaved = image.planes().interleaved.ok_or_else(|| {
+        ConvertError::DecodeError("Failed to get interleaved RGB data".to_string())
+    })?;
 
-        // Copy pixel data with pre-sized capacity
-        let stride = interleaved.stride;
-        let capacity = (width as usize) * (height as usize) * 3;
-        let mut rgb_data = Vec::with_capacity(capacity);
+    // Untransformed pixels may have width and height swapped
+    let width = interleaved.width;
+    let height = interleaved.height;
+    let stride = interleaved.stride;
 
+    let (pixels, icc) = if high_bit_depth {
+        // Reduce to 8 bits, tone-mapping PQ/HLG content into SDR range
+        let row_len = width as usize * channels;
+        let mut pixels = buffers::take(row_len * height as usize);
+        let transfer = nclx
+            .as_ref()
+            .and_then(|nclx| HdrTransfer::from_characteristics(nclx.transfer_characteristics()));
+        let mapper = ToneMapper::new(interleaved.bits_per_pixel, transfer, options.tone_map);
         for y in 0..height as usize {
             let row_start = y * stride;
-            let row_end = row_start + (width as usize * 3);
-            rgb_data.extend_from_slice(&interleaved.data[row_start..row_end]);
+            mapper.map_row(
+                &interleaved.data[row_start..row_start + row_len * 2],
+                channels,
+                &mut pixels,
+            );
         }
 
-        Ok((rgb_data, width, height))
+        // Tone-mapped pixels no longer follow the source transfer curve
+        let icc = if mapper.is_hdr() {
+            nclx.as_ref().and_then(color::sdr_icc)
+        } else {
+            color::resolve_icc(raw_profile, nclx)
+        };
+        (Pixels::Packed(pixels), icc)
+    } else {
+        // 8-bit rows stay in the libheif plane until something needs them packed
+        (
+            Pixels::Heif { image, stride },
+            color::resolve_icc(raw_profile, nclx),
+        )
+    };
+
+    Ok(DecodedImage {
+        pixels,
+        width,
+        height,
+        has_alpha,
+        premultiplied_alpha: has_alpha && handle.is_premultiplied_alpha(),
+        ycbcr: None,
+        exif: None,
+        icc,
+        orientation: 1,
+        layers: Vec::new(),
     })
 }
 
-/// Encode RGB buffer to JPEG bytes using thread-local compressor
+/// Decide whether `handle` can skip RGB and go to JPEG as coded YCbCr
+///
+/// Only applies when the output is a JPEG that needs no pixel-space work:
+/// no colour conversion, resizing, alpha flattening or bit depth reduction,
+/// and a chroma layout matching the requested subsampling.
+fn ycbcr_fast_path(
+    handle: &ImageHandle,
+    metadata_source: &ImageHandle,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Option<(ColorSpace, Subsamp)> {
+    if !options.ycbcr_fast_path
+        || options.format != OutputFormat::Jpeg
+        || options.to_srgb
+        || options.crop.is_some()
+        || options.width.is_some()
+        || options.height.is_some()
+        || options.downscale_fallback
+        || handle.has_alpha_channel()
+        || handle.luma_bits_per_pixel() != 8
+        || handle.chroma_bits_per_pixel() != 8
+    {
+        return None;
+    }
+
+    let nclx = handle
+        .color_profile_nclx()
+        .or_else(|| metadata_source.color_profile_nclx());
+    if !ycbcr::is_jfif_compatible(nclx.as_ref()) {
+        return None;
+    }
+
+    let colorspace = handle.preferred_decoding_colorspace().ok()?;
+    let subsamp = ycbcr::jpeg_subsamp(colorspace)?;
+    match options.subsampling {
+        ChromaSubsampling::Auto => Some((colorspace, subsamp)),
+        requested
+            if requested.resolve(quality.clamp(options.min_quality, options.max_quality))
+                == subsamp =>
+        {
+            Some((colorspace, subsamp))
+        }
+        _ => None,
+    }
+}
+
+/// Decode to YCbCr planes packed for turbojpeg's planar compressor
+fn decode_ycbcr(
+    lib_heif: &LibHeif,
+    handle: &ImageHandle,
+    metadata_source: &ImageHandle,
+    colorspace: ColorSpace,
+    subsamp: Subsamp,
+    decoding_options: Option<DecodingOptions>,
+) -> Result<DecodedImage, ConvertError> {
+    let image = lib_heif
+        .decode(handle, colorspace, decoding_options)
+        .map_err(limits::heif_error)?;
+
+    let planes = image.planes();
+    let missing = || ConvertError::DecodeError("Failed to get YCbCr plane data".to_string());
+    fn plane<'a>(p: libheif_rs::Plane<&'a [u8]>) -> ycbcr::Plane<'a> {
+        ycbcr::Plane {
+            data: p.data,
+            stride: p.stride,
+            width: p.width as usize,
+            height: p.height as usize,
+        }
+    }
+
+    let y = planes.y.ok_or_else(missing)?;
+    let (width, height) = (y.width, y.height);
+    let mut source = vec![plane(y)];
+    if subsamp != Subsamp::Gray {
+        source.push(plane(planes.cb.ok_or_else(missing)?));
+        source.push(plane(planes.cr.ok_or_else(missing)?));
+    }
+
+    let mut pixels = buffers::take(ycbcr::packed_len(width as usize, height as usize, subsamp));
+    ycbcr::pack_planes(
+        &source,
+        width as usize,
+        height as usize,
+        subsamp,
+        &mut pixels,
+    );
+
+    let (raw_profile, nclx) = source_profiles(handle, &image, metadata_source);
+
+    Ok(DecodedImage {
+        pixels: Pixels::Packed(pixels),
+        width,
+        height,
+        has_alpha: false,
+        premultiplied_alpha: false,
+        ycbcr: Some(subsamp),
+        exif: None,
+        icc: color::resolve_icc(raw_profile, nclx),
+        orientation: 1,
+        layers: Vec::new(),
+    })
+}
+
+/// Pixels handed to the JPEG compressor
+enum JpegSource<'a> {
+    /// Interleaved RGB or grayscale, converted to YCbCr by turbojpeg
+    Packed(Image<&'a [u8]>),
+    /// YCbCr planes compressed as-is
+    Planar(YuvImage<&'a [u8]>),
+}
+
+/// Encode pixels to JPEG bytes using thread-local compressor
+///
+/// `app_segments` are complete marker segments (e.g. Exif APP1) spliced in
+/// after the JFIF header, followed by `icc` as APP2 segments unless the
+/// output is grayscale, which an RGB profile does not describe. Quality
+/// bounds and entropy coding settings come from `options`.
 fn encode_jpeg(
-    rgb_data: &[u8],
-    width: u32,
-    height: u32,
+    source: JpegSource,
     quality: u8,
-    min_q: u8,
-    max_q: u8,
+    options: &ConvertOptions,
+    mut app_segments: Vec<Vec<u8>>,
+    icc: Option<&[u8]>,
 ) -> Result<Vec<u8>, ConvertError> {
     // Validate and clamp quality
-    let quality = quality.clamp(min_q, max_q);
+    let quality = quality.clamp(options.min_quality, options.max_quality);
 
     with_compressor(|compressor| {
         compressor
             .set_quality(quality as i32)
             .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+        // The compressor is reused across requests, so always set it
         compressor
-            .set_subsamp(turbojpeg::Subsamp::Sub2x2)
-            .map_err(|e| ConvertError::EncodeError(e.to_string()))?; // 4:2:0 chroma subsampling
-
-        // Create image wrapper
-        let image = Image {
-            pixels: rgb_data,
-            width: width as usize,
-            pitch: width as usize * 3,
-            height: height as usize,
-            format: PixelFormat::RGB,
+            .set_optimize(options.optimize)
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+        let subsamp = match &source {
+            JpegSource::Packed(image) if image.format == PixelFormat::GRAY => Subsamp::Gray,
+            JpegSource::Packed(_) => options.subsampling.resolve(quality),
+            JpegSource::Planar(yuv) => yuv.subsamp,
         };
+        compressor
+            .set_subsamp(subsamp)
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+        if let Some(icc) = icc.filter(|_| subsamp != Subsamp::Gray) {
+            app_segments.extend(metadata::icc_segments(icc));
+        }
 
-        // Compress
-        let jpeg_data = compressor
-            .compress_to_vec(image)
+        // Compress into a pooled worst-case sized buffer, then copy out
+        // only the bytes written
+        let (width, height) = match &source {
+            JpegSource::Packed(image) => (image.width, image.height),
+            JpegSource::Planar(yuv) => (yuv.width, yuv.height),
+        };
+        let buf_len = compressor
+            .buf_len(width, height)
             .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+        let mut scratch = buffers::take_filled(buf_len);
+        let mut output = OutputBuf::borrowed(&mut scratch);
+        match source {
+            JpegSource::Packed(image) => compressor.compress(image, &mut output),
+            JpegSource::Planar(yuv) => compressor.compress_yuv(yuv, &mut output),
+        }
+        .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
 
+        // The compressor only writes baseline scans; progressive output is
+        // a lossless transform of them, before the segments are spliced in
+        let jpeg_data = if options.progressive {
+            let mut transform = Transform::op(TransformOp::None);
+            transform.progressive = true;
+            let progressive = turbojpeg::transform(&transform, &output)
+                .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+            metadata::insert_segments(&progressive, &app_segments)
+        } else {
+            metadata::insert_segments(&output, &app_segments)
+        };
+        drop(output);
+        buffers::give(scratch);
         Ok(jpeg_data)
     })
 }
 
+/// Encode a pixel buffer to PNG bytes, optionally tagged with an ICC profile
+fn encode_png(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ExtendedColorType,
+    icc: Option<&[u8]>,
+) -> Result<Vec<u8>, ConvertError> {
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    if let Some(icc) = icc {
+        encoder
+            .set_icc_profile(icc.to_vec())
+            .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+    }
+    encoder
+        .write_image(pixels, width, height, color)
+        .map_err(|e| ConvertError::EncodeError(e.to_string()))?;
+    Ok(png)
+}
+
+/// Encode an RGB or RGBA buffer to WebP bytes
+///
+/// `quality` drives the lossy encoder; in lossless mode it trades encoding
+/// effort for size.
+fn encode_webp(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    quality: u8,
+    lossless: bool,
+) -> Result<Vec<u8>, ConvertError> {
+    let encoder = if has_alpha {
+        webp::Encoder::from_rgba(pixels, width, height)
+    } else {
+        webp::Encoder::from_rgb(pixels, width, height)
+    };
+    let webp = encoder
+        .encode_simple(lossless, quality as f32)
+        .map_err(|e| ConvertError::EncodeError(format!("WebP encoding failed: {:?}", e)))?;
+    Ok(webp.to_vec())
+}
+
+/// Encode an RGB or RGBA buffer to AVIF bytes with libheif's AV1 encoder
+///
+/// The ICC profile and Exif block are stored as HEIF properties and items;
+/// alpha goes into an auxiliary image. Lossless output is coded as RGB by
+/// [`avif::encode_lossless`].
+fn encode_avif(
+    decoded: &DecodedImage,
+    exif: Option<&[u8]>,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    if options.lossless {
+        // LibHeif keeps libheif initialised while the C API is used directly
+        return LIB_HEIF.with(|_| {
+            avif::encode_lossless(
+                decoded.rows(),
+                (decoded.width, decoded.height),
+                decoded.has_alpha,
+                decoded.icc.as_deref(),
+                exif,
+                options.speed,
+            )
+        });
+    }
+
+    let encode_error = |e: libheif_rs::HeifError| ConvertError::EncodeError(e.to_string());
+    let (chroma, channels) = if decoded.has_alpha {
+        (RgbChroma::Rgba, 4)
+    } else {
+        (RgbChroma::Rgb, 3)
+    };
+
+    let mut image = libheif_rs::Image::new(decoded.width, decoded.height, ColorSpace::Rgb(chroma))
+        .map_err(encode_error)?;
+    image
+        .create_plane(Channel::Interleaved, decoded.width, decoded.height, 8)
+        .map_err(encode_error)?;
+    {
+        let planes = image.planes_mut();
+        let plane = planes.interleaved.ok_or_else(|| {
+            ConvertError::EncodeError("Failed to get interleaved plane".to_string())
+        })?;
+        let row_len = decoded.width as usize * channels;
+        let (data, stride) = decoded.rows();
+        for (dst, src) in plane.data.chunks_mut(plane.stride).zip(data.chunks(stride)) {
+            dst[..row_len].copy_from_slice(&src[..row_len]);
+        }
+    }
+    if let Some(icc) = &decoded.icc {
+        image
+            .set_color_profile_raw(&ColorProfileRaw::new(
+                color_profile_types::PROF,
+                icc.clone(),
+            ))
+            .map_err(encode_error)?;
+    }
+
+    LIB_HEIF.with(|lib_heif| {
+        let mut encoder = lib_heif
+            .encoder_for_format(CompressionFormat::Av1)
+            .map_err(encode_error)?;
+        encoder
+            .set_quality(EncoderQuality::Lossy(quality))
+            .map_err(encode_error)?;
+        encoder
+            .set_parameter_value("speed", EncoderParameterValue::Int(options.speed as i32))
+            .map_err(encode_error)?;
+
+        let mut ctx = HeifContext::new().map_err(encode_error)?;
+        let handle = ctx
+            .encode_image(&image, &mut encoder, None)
+            .map_err(encode_error)?;
+        if let Some(tiff) = exif {
+            ctx.add_exif_metadata(&handle, tiff).map_err(encode_error)?;
+        }
+        ctx.write_to_bytes().map_err(encode_error)
+    })
+}
+
+/// JPEG chroma subsampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// 4:4:4 at high qualities, 4:2:0 otherwise
+    Auto,
+    /// Full chroma resolution
+    S444,
+    /// Half horizontal chroma resolution
+    S422,
+    /// Half horizontal and vertical chroma resolution
+    S420,
+    /// Half vertical chroma resolution
+    S440,
+    /// Luma only, producing a grayscale JPEG
+    Gray,
+}
+
+impl ChromaSubsampling {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::S444 => "444",
+            Self::S422 => "422",
+            Self::S420 => "420",
+            Self::S440 => "440",
+            Self::Gray => "gray",
+        }
+    }
+
+    /// turbojpeg setting for a given (clamped) quality
+    fn resolve(self, quality: u8) -> Subsamp {
+        match self {
+            Self::Auto if quality >= AUTO_FULL_CHROMA_QUALITY => Subsamp::None,
+            Self::Auto => Subsamp::Sub2x2,
+            Self::S444 => Subsamp::None,
+            Self::S422 => Subsamp::Sub2x1,
+            Self::S420 => Subsamp::Sub2x2,
+            Self::S440 => Subsamp::Sub1x2,
+            Self::Gray => Subsamp::Gray,
+        }
+    }
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "444" | "4:4:4" => Ok(Self::S444),
+            "422" | "4:2:2" => Ok(Self::S422),
+            "420" | "4:2:0" => Ok(Self::S420),
+            "440" | "4:4:0" => Ok(Self::S440),
+            "gray" | "grey" => Ok(Self::Gray),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid subsampling: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Output file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    /// Lossless, keeps alpha
+    Png,
+    /// Lossy or lossless, keeps alpha
+    WebP,
+    /// AV1 in HEIF, keeps alpha
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// Whether `quality` controls the output size
+    fn is_lossy(self, lossless: bool) -> bool {
+        match self {
+            Self::Jpeg => true,
+            Self::Png => false,
+            Self::WebP | Self::Avif => !lossless,
+        }
+    }
+
+    /// Whether transparency survives encoding
+    fn supports_alpha(self) -> bool {
+        match self {
+            Self::Jpeg => false,
+            Self::Png | Self::WebP | Self::Avif => true,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "webp" => Ok(Self::WebP),
+            "avif" => Ok(Self::Avif),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid format: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// How `irot`/`imir` transformations reach the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrientationMode {
+    /// Rotate the pixels during decode and reset Exif Orientation to 1
+    Apply,
+    /// Keep the coded pixels and describe the rotation in Exif Orientation
+    Preserve,
+}
+
+impl OrientationMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Apply => "apply",
+            Self::Preserve => "preserve",
+        }
+    }
+}
+
+impl FromStr for OrientationMode {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "apply" => Ok(Self::Apply),
+            "preserve" => Ok(Self::Preserve),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid orientation: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which images of a (possibly multi-image) HEIF file to convert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSelection {
+    /// The primary image only
+    Primary,
+    /// A single top-level image by 0-based position
+    Index(usize),
+    /// Every top-level image, returned as a ZIP archive
+    All,
+}
+
 /// Conversion options
 pub struct ConvertOptions {
-    pub max_resolution: u32,
+    /// Size and memory limits checked before each image is decoded
+    pub limits: Limits,
+    /// Output file format
+    pub format: OutputFormat,
+    /// Use lossless compression (WebP, AVIF)
+    pub lossless: bool,
+    /// AVIF encoder speed, 0 (slowest, smallest) to 9 (fastest)
+    pub speed: u8,
+    /// Write progressive JPEG scans, by losslessly transforming the
+    /// baseline output
+    pub progressive: bool,
+    /// Compute optimal JPEG Huffman tables
+    pub optimize: bool,
+    /// JPEG chroma subsampling
+    pub subsampling: ChromaSubsampling,
+    /// Encode JPEGs straight from the coded YCbCr planes when no pixel-space
+    /// operation is requested
+    pub ycbcr_fast_path: bool,
+    /// Pick the highest quality whose output fits in this many bytes
+    pub max_bytes: Option<usize>,
+    /// Shrink the image when `max_bytes` is out of reach at minimum quality
+    pub downscale_fallback: bool,
     pub min_quality: u8,
     pub max_quality: u8,
+    /// Copy the source Exif block into the output
+    pub keep_metadata: bool,
+    /// Transform pixels into sRGB instead of embedding the source profile
+    pub to_srgb: bool,
+    /// Rendering intent used by `to_srgb`
+    pub rendering_intent: RenderingIntent,
+    /// Whether rotation is baked into the pixels or kept in Exif
+    pub orientation: OrientationMode,
+    /// Region to cut out before resizing, in display orientation
+    pub crop: Option<Crop>,
+    /// Anchor for a `crop` without position and for `cover`/`contain` fits
+    pub gravity: Gravity,
+    /// Target width in pixels (`None` keeps the source size or aspect ratio)
+    pub width: Option<u32>,
+    /// Target height in pixels (`None` keeps the source size or aspect ratio)
+    pub height: Option<u32>,
+    /// How to fit the image when both `width` and `height` are set
+    pub fit: FitMode,
+    /// Decode from an embedded thumbnail whose longer side covers this size,
+    /// if the file has one (primary image only)
+    pub thumbnail: Option<u32>,
+    /// Which top-level images to convert
+    pub images: ImageSelection,
+    /// Export depth and auxiliary layers in this format alongside the image
+    pub layers: Option<LayerFormat>,
+    /// Colour transparent pixels and `contain` padding are flattened onto
+    pub background: [u8; 3],
+    /// Operator compressing HDR (PQ/HLG) highlights into 8-bit SDR
+    pub tone_map: ToneMapOperator,
+}
+
+impl ConvertOptions {
+    /// Options with the configured limits and default per-request settings
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            limits: Limits::from_config(config),
+            format: OutputFormat::Jpeg,
+            lossless: false,
+            speed: 6,
+            progressive: false,
+            optimize: false,
+            subsampling: ChromaSubsampling::S420,
+            ycbcr_fast_path: config.ycbcr_fast_path,
+            max_bytes: None,
+            downscale_fallback: false,
+            min_quality: config.min_quality,
+            max_quality: config.max_quality,
+            // Exif carries capture time and GPS location; clients opt in
+            keep_metadata: false,
+            to_srgb: false,
+            rendering_intent: RenderingIntent::Perceptual,
+            orientation: OrientationMode::Apply,
+            crop: None,
+            gravity: Gravity::Center,
+            width: None,
+            height: None,
+            fit: FitMode::Inside,
+            thumbnail: None,
+            images: ImageSelection::Primary,
+            layers: None,
+            background: [255, 255, 255],
+            tone_map: ToneMapOperator::Reinhard,
+        }
+    }
+}
+
+/// Encoded result of a conversion
+pub struct ConvertOutput {
+    pub data: Vec<u8>,
+    /// MIME type of `data`
+    pub content_type: &'static str,
+    /// File extension for the download name
+    pub extension: &'static str,
+    /// Quality the output was encoded with; the lowest one across a ZIP
+    pub quality: u8,
 }
 
-/// Convert HEIC bytes to JPEG bytes
+/// Convert HEIC bytes (or a JPEG, PNG or WebP) to the requested output format
+///
+/// With [`ImageSelection::All`] or layer export the results are packed into
+/// a ZIP archive together with a `manifest.json` listing its contents.
 ///
 /// # Arguments
-/// * `heic_data` - Raw HEIC file bytes
-/// * `quality` - JPEG quality (60-95)
+/// * `input` - Raw HEIF-family, JPEG, PNG or WebP file bytes
+/// * `quality` - Lossy encoding quality (60-95)
 /// * `options` - Conversion limits and options
 pub fn convert(
-    heic_data: &[u8],
+    input: &[u8],
     quality: u8,
     options: &ConvertOptions,
-) -> Result<Vec<u8>, ConvertError> {
+) -> Result<ConvertOutput, ConvertError> {
     // Validate quality
     if quality < options.min_quality || quality > options.max_quality {
         return Err(ConvertError::InvalidQuality(quality));
     }
 
-    // Decode HEIC to RGB
-    let (rgb_data, width, height) = decode_heic(heic_data, options.max_resolution)?;
+    if options.images == ImageSelection::All || options.layers.is_some() {
+        let mut zip = ZipArchive::new();
+        let mut manifest = Manifest::default();
+        let mut lowest_quality = quality;
 
-    // Encode RGB to JPEG
-    let jpeg_data = encode_jpeg(
-        &rgb_data,
-        width,
-        height,
+        decode_input(input, quality, options, |index, mut decoded| {
+            let layers = std::mem::take(&mut decoded.layers);
+            let file = format!("image_{:03}.{}", index, options.format.extension());
+            let (data, used_quality) = encode_image(decoded, quality, options)?;
+            zip.add(&file, &data)?;
+            lowest_quality = lowest_quality.min(used_quality);
+
+            let mut entry = ManifestImage {
+                index,
+                file,
+                layers: Vec::new(),
+            };
+            if let Some(format) = options.layers {
+                for (n, layer) in layers.iter().enumerate() {
+                    // Number layers per kind: depth_0, aux_0, aux_1, ...
+                    let nth = layers[..n].iter().filter(|l| l.kind == layer.kind).count();
+                    let file = format!(
+                        "image_{:03}_{}_{}.{}",
+                        index,
+                        layer.kind.as_str(),
+                        nth,
+                        format.extension()
+                    );
+                    zip.add(&file, &encode_layer(layer, format, quality, options)?)?;
+                    entry.layers.push(ManifestLayer::new(file, layer));
+                }
+            }
+            manifest.images.push(entry);
+            Ok(())
+        })?;
+
+        let manifest = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| ConvertError::Internal(e.to_string()))?;
+        zip.add("manifest.json", &manifest)?;
+
+        return Ok(ConvertOutput {
+            data: zip.finish()?,
+            content_type: "application/zip",
+            extension: "zip",
+            quality: lowest_quality,
+        });
+    }
+
+    let mut encoded = None;
+    decode_input(input, quality, options, |_, decoded| {
+        encoded = Some(encode_image(decoded, quality, options)?);
+        Ok(())
+    })?;
+    let (data, quality) =
+        encoded.ok_or_else(|| ConvertError::Internal("No image decoded".to_string()))?;
+
+    Ok(ConvertOutput {
+        data,
+        content_type: options.format.content_type(),
+        extension: options.format.extension(),
         quality,
-        options.min_quality,
-        options.max_quality,
-    )?;
+    })
+}
+
+/// Apply the requested pixel operations to a decoded image and encode it
+///
+/// Returns the encoded bytes and the quality used, which differs from
+/// `quality` when searching for `max_bytes`.
+fn encode_image(
+    mut decoded: DecodedImage,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Result<(Vec<u8>, u8), ConvertError> {
+    // Gamut-map into sRGB; the output is then untagged
+    if options.to_srgb {
+        if let Some(icc) = decoded.icc.take() {
+            let has_alpha = decoded.has_alpha;
+            color::convert_to_srgb(decoded.packed(), has_alpha, &icc, options.rendering_intent)?;
+        }
+    }
+
+    // Crop and resize work in display orientation; with a preserved
+    // rotation, map the rectangle, box and gravity onto the coded pixels
+    let gravity = options.gravity.to_coded(decoded.orientation);
+    if let Some(request) = &options.crop {
+        let coded = (decoded.width, decoded.height);
+        let display = if decoded.orientation >= 5 {
+            (coded.1, coded.0)
+        } else {
+            coded
+        };
+        let rect = request
+            .resolve(display, options.gravity)?
+            .to_coded(decoded.orientation, coded);
+
+        let channels = if decoded.has_alpha { 4 } else { 3 };
+        let (data, stride) = decoded.rows();
+        let mut cropped = buffers::take(rect.width as usize * rect.height as usize * channels);
+        crop::crop(data, stride, channels, rect, &mut cropped);
+        let previous = std::mem::replace(&mut decoded.pixels, Pixels::Packed(cropped));
+        if let Pixels::Packed(buf) = previous {
+            buffers::give(buf);
+        }
+        decoded.width = rect.width;
+        decoded.height = rect.height;
+    }
+
+    if options.width.is_some() || options.height.is_some() {
+        let target = if decoded.orientation >= 5 {
+            (options.height, options.width)
+        } else {
+            (options.width, options.height)
+        };
+        resize::check(
+            (decoded.width, decoded.height),
+            target,
+            options.fit,
+            decoded.has_alpha,
+            &options.limits,
+        )?;
+        let (pixels, w, h) = resize::resize(
+            decoded.take_packed(),
+            decoded.has_alpha,
+            (decoded.width, decoded.height),
+            target,
+            options.fit,
+            gravity,
+            options.background,
+        )?;
+        decoded.pixels = Pixels::Packed(pixels);
+        decoded.width = w;
+        decoded.height = h;
+    }
+
+    // Composite onto the background colour for formats without alpha;
+    // the others expect straight alpha
+    if decoded.has_alpha && !options.format.supports_alpha() {
+        let rgba = decoded.take_packed();
+        let rgb = color::flatten_alpha(&rgba, options.background, decoded.premultiplied_alpha);
+        buffers::give(rgba);
+        decoded.pixels = Pixels::Packed(rgb);
+        decoded.has_alpha = false;
+    } else if decoded.premultiplied_alpha {
+        color::unpremultiply_alpha(decoded.packed());
+    }
+
+    // Exif Orientation must describe the pixels actually written; a
+    // preserved rotation needs an Exif block even when metadata is stripped
+    let orientation = decoded.orientation;
+    let exif = decoded
+        .exif
+        .take()
+        .filter(|_| options.keep_metadata)
+        .and_then(|mut tiff| metadata::set_orientation(&mut tiff, orientation).then_some(tiff));
+    decoded.exif = match exif {
+        Some(tiff) => Some(tiff),
+        None if orientation != 1 => Some(metadata::orientation_exif(orientation)),
+        None => None,
+    };
+
+    // PNG and WebP encoders only take packed rows
+    if matches!(options.format, OutputFormat::Png | OutputFormat::WebP) {
+        decoded.packed();
+    }
 
-    Ok(jpeg_data)
+    let result = match options.max_bytes {
+        Some(max_bytes) => encode_within(&mut decoded, max_bytes, options),
+        None => encode_pixels(&decoded, quality, options).map(|data| (data, quality)),
+    };
+    decoded.release();
+    result
+}
+
+/// Encode at the highest quality whose output fits in `max_bytes`
+///
+/// Binary-searches `min_quality..=max_quality`, re-encoding the same prepared
+/// buffer on every attempt. With `downscale_fallback` the image is shrunk
+/// step by step when even the minimum quality is too large.
+fn encode_within(
+    decoded: &mut DecodedImage,
+    max_bytes: usize,
+    options: &ConvertOptions,
+) -> Result<(Vec<u8>, u8), ConvertError> {
+    loop {
+        // Quality does not change the size of lossless output; one try is enough
+        let mut lo = if options.format.is_lossy(options.lossless) {
+            options.min_quality
+        } else {
+            options.max_quality
+        };
+        let mut hi = options.max_quality;
+        let mut best = None;
+        let mut smallest = usize::MAX;
+
+        while lo <= hi {
+            let mid = lo + (hi - lo) / 2;
+            let data = encode_pixels(decoded, mid, options)?;
+            if data.len() <= max_bytes {
+                best = Some((data, mid));
+                lo = mid + 1;
+            } else {
+                smallest = smallest.min(data.len());
+                match mid.checked_sub(1) {
+                    Some(q) => hi = q,
+                    None => break,
+                }
+            }
+        }
+        if let Some(best) = best {
+            return Ok(best);
+        }
+
+        let width = (decoded.width as f64 * DOWNSCALE_STEP).round() as u32;
+        let height = (decoded.height as f64 * DOWNSCALE_STEP).round() as u32;
+        if !options.downscale_fallback || width.max(height) < MIN_DOWNSCALE_SIDE {
+            return Err(ConvertError::TargetSizeUnreachable {
+                max_bytes,
+                smallest,
+            });
+        }
+
+        let (pixels, w, h) = resize::resize(
+            decoded.take_packed(),
+            decoded.has_alpha,
+            (decoded.width, decoded.height),
+            (Some(width.max(1)), Some(height.max(1))),
+            FitMode::Fill,
+            Gravity::Center,
+            options.background,
+        )?;
+        decoded.pixels = Pixels::Packed(pixels);
+        decoded.width = w;
+        decoded.height = h;
+    }
+}
+
+/// Encode prepared pixels in the requested output format
+fn encode_pixels(
+    decoded: &DecodedImage,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    let exif = decoded.exif.as_deref();
+    match options.format {
+        OutputFormat::Jpeg => {
+            // Collect metadata segments for the output
+            let mut app_segments = Vec::new();
+            if let Some(segment) = exif.and_then(metadata::exif_segment) {
+                app_segments.push(segment);
+            }
+
+            // Encode coded YCbCr planes directly, or RGB rows in place
+            let (pixels, stride) = decoded.rows();
+            let source = match decoded.ycbcr {
+                Some(subsamp) => JpegSource::Planar(YuvImage {
+                    pixels,
+                    width: decoded.width as usize,
+                    align: 1,
+                    height: decoded.height as usize,
+                    subsamp,
+                }),
+                None => JpegSource::Packed(Image {
+                    pixels,
+                    width: decoded.width as usize,
+                    pitch: stride,
+                    height: decoded.height as usize,
+                    format: PixelFormat::RGB,
+                }),
+            };
+            encode_jpeg(
+                source,
+                quality,
+                options,
+                app_segments,
+                decoded.icc.as_deref(),
+            )
+        }
+        OutputFormat::Png => {
+            let color = if decoded.has_alpha {
+                ExtendedColorType::Rgba8
+            } else {
+                ExtendedColorType::Rgb8
+            };
+            let png = encode_png(
+                decoded.rows().0,
+                decoded.width,
+                decoded.height,
+                color,
+                decoded.icc.as_deref(),
+            )?;
+            Ok(match exif {
+                Some(tiff) => metadata::insert_png_exif(png, tiff),
+                None => png,
+            })
+        }
+        OutputFormat::WebP => {
+            let webp = encode_webp(
+                decoded.rows().0,
+                decoded.width,
+                decoded.height,
+                decoded.has_alpha,
+                quality.clamp(options.min_quality, options.max_quality),
+                options.lossless,
+            )?;
+            Ok(metadata::insert_webp_metadata(
+                webp,
+                decoded.width,
+                decoded.height,
+                decoded.has_alpha,
+                decoded.icc.as_deref(),
+                exif,
+            ))
+        }
+        OutputFormat::Avif => encode_avif(
+            decoded,
+            exif,
+            quality.clamp(options.min_quality, options.max_quality),
+            options,
+        ),
+    }
+}
+
+/// Encode a depth or auxiliary layer as a standalone grayscale file
+fn encode_layer(
+    layer: &Layer,
+    format: LayerFormat,
+    quality: u8,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    match format {
+        LayerFormat::Jpeg => {
+            let samples = layer.samples_8bit();
+            let image = Image {
+                pixels: samples.as_ref(),
+                width: layer.width as usize,
+                pitch: layer.width as usize,
+                height: layer.height as usize,
+                format: PixelFormat::GRAY,
+            };
+            encode_jpeg(
+                JpegSource::Packed(image),
+                quality,
+                options,
+                Vec::new(),
+                None,
+            )
+        }
+        LayerFormat::Png => {
+            if layer.bit_depth > 8 {
+                let samples = layer.samples_16bit();
+                encode_png(
+                    &samples,
+                    layer.width,
+                    layer.height,
+                    ExtendedColorType::L16,
+                    None,
+                )
+            } else {
+                encode_png(
+                    &layer.pixels,
+                    layer.width,
+                    layer.height,
+                    ExtendedColorType::L8,
+                    None,
+                )
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use libheif_rs::MatrixCoefficients;
+    use std::time::Instant;
 
     #[test]
     fn test_invalid_quality() {
-        let options = ConvertOptions {
-            max_resolution: 1000,
-            min_quality: 60,
-            max_quality: 95,
-        };
+        let mut options = ConvertOptions::from_config(&Config::from_env());
+        options.min_quality = 60;
+        options.max_quality = 95;
         let result = convert(&[], 50, &options);
         assert!(matches!(result, Err(ConvertError::InvalidQuality(50))));
     }
+
+    #[test]
+    fn test_orientation_mode_parse() {
+        assert_eq!(
+            "Preserve".parse::<OrientationMode>().unwrap(),
+            OrientationMode::Preserve
+        );
+        assert_eq!(OrientationMode::Apply.as_str(), "apply");
+        assert!("rotate".parse::<OrientationMode>().is_err());
+    }
+
+    #[test]
+    fn test_output_format_parse() {
+        let format: OutputFormat = "PNG".parse().unwrap();
+        assert_eq!(format, OutputFormat::Png);
+        assert_eq!(format.content_type(), "image/png");
+        assert_eq!("jpg".parse::<OutputFormat>().unwrap().extension(), "jpg");
+        assert_eq!(
+            "WebP".parse::<OutputFormat>().unwrap().content_type(),
+            "image/webp"
+        );
+        assert!("gif".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_chroma_subsampling() {
+        let auto: ChromaSubsampling = "auto".parse().unwrap();
+        assert_eq!(auto.resolve(95), Subsamp::None);
+        assert_eq!(auto.resolve(85), Subsamp::Sub2x2);
+        assert_eq!(
+            "4:2:2".parse::<ChromaSubsampling>().unwrap(),
+            ChromaSubsampling::S422
+        );
+        assert_eq!(ChromaSubsampling::S440.resolve(60), Subsamp::Sub1x2);
+        assert!("411".parse::<ChromaSubsampling>().is_err());
+    }
+
+    #[test]
+    fn test_lossless_avif_round_trip() {
+        let (width, height) = (32u32, 24u32);
+        let pixels: Vec<u8> = (0..height)
+            .flat_map(|y| {
+                (0..width).flat_map(move |x| [(x * 8) as u8, (y * 10) as u8, (x * y) as u8])
+            })
+            .collect();
+        let png = encode_png(&pixels, width, height, ExtendedColorType::Rgb8, None).unwrap();
+
+        let mut options = ConvertOptions::from_config(&Config::from_env());
+        options.format = OutputFormat::Avif;
+        options.lossless = true;
+        let output = convert(&png, 90, &options).unwrap();
+
+        let ctx = HeifContext::read_from_bytes(&output.data).unwrap();
+        let handle = ctx.primary_image_handle().unwrap();
+        let nclx = handle.color_profile_nclx().unwrap();
+        assert_eq!(nclx.matrix_coefficients(), MatrixCoefficients::RGB_GBR);
+        assert_eq!(nclx.full_range_flag(), 1);
+        let image = LibHeif::new()
+            .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+            .unwrap();
+        let plane = image.planes().interleaved.unwrap();
+        let row_len = width as usize * 3;
+        for (decoded, source) in plane.data.chunks(plane.stride).zip(pixels.chunks(row_len)) {
+            assert_eq!(&decoded[..row_len], source);
+        }
+    }
+
+    #[test]
+    fn test_estimate_memory_counts_resize() {
+        let png = encode_png(&[0; 3], 1, 1, ExtendedColorType::Rgb8, None).unwrap();
+        let mut options = ConvertOptions::from_config(&Config::from_env());
+        assert_eq!(estimate_memory(&png, &options).unwrap(), 12);
+
+        options.width = Some(1000);
+        options.height = Some(500);
+        options.fit = FitMode::Fill;
+        assert_eq!(
+            estimate_memory(&png, &options).unwrap(),
+            12 + 1000 * 500 * 12
+        );
+
+        // A ZIP also holds the encoded output, counted uncompressed
+        options.images = ImageSelection::All;
+        assert_eq!(
+            estimate_memory(&png, &options).unwrap(),
+            12 + 1000 * 500 * 12 + 1000 * 500 * 3
+        );
+        options.images = ImageSelection::Primary;
+
+        // Upscaling a 1x1 upload to the side limit is refused before queueing
+        options.width = Some(16384);
+        options.height = Some(16384);
+        assert!(matches!(
+            estimate_memory(&png, &options),
+            Err(ConvertError::TooManyPixels { .. })
+        ));
+    }
+
+    /// Compare the YCbCr fast path with the RGB path on the benchmark samples:
+    /// `cargo test --release -- --ignored --nocapture bench_ycbcr_fast_path`
+    #[test]
+    #[ignore]
+    fn bench_ycbcr_fast_path() {
+        const ROUNDS: usize = 5;
+
+        let samples: Vec<Vec<u8>> = std::fs::read_dir("static/benchmark")
+            .expect("benchmark samples missing")
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "heic"))
+            .map(|path| std::fs::read(path).unwrap())
+            .collect();
+        assert!(!samples.is_empty());
+
+        let config = Config::from_env();
+        for fast_path in [false, true] {
+            let mut options = ConvertOptions::from_config(&config);
+            options.ycbcr_fast_path = fast_path;
+
+            // Warm up thread-local decoder and compressor
+            convert(&samples[0], 85, &options).unwrap();
+
+            let start = Instant::now();
+            let mut bytes = 0;
+            for _ in 0..ROUNDS {
+                for sample in &samples {
+                    bytes += convert(sample, 85, &options).unwrap().data.len();
+                }
+            }
+            let per_image =
+                start.elapsed().as_secs_f64() * 1000.0 / (ROUNDS * samples.len()) as f64;
+            println!(
+                "{:>5} path: {:.1} ms/image, {} bytes/image",
+                if fast_path { "ycbcr" } else { "rgb" },
+                per_image,
+                bytes / (ROUNDS * samples.len())
+            );
+        }
+    }
 }
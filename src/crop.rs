@@ -0,0 +1,370 @@
+//! Crop rectangles and gravity
+//!
+//! Crop coordinates and gravity are given in display orientation. When an
+//! Exif rotation is preserved rather than applied, they are mapped onto the
+//! coded pixels before use.
+
+use crate::error::ConvertError;
+use std::str::FromStr;
+
+/// A crop coordinate, absolute or relative to the image size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(u32),
+    /// Percentage of the image width or height, 0-100
+    Percent(f64),
+}
+
+impl Length {
+    fn resolve(self, full: u32) -> f64 {
+        match self {
+            Self::Pixels(px) => px as f64,
+            Self::Percent(p) => full as f64 * p / 100.0,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || ConvertError::ValidationError(format!("Invalid crop value: {}", s));
+        match s.strip_suffix('%') {
+            Some(p) => {
+                let p: f64 = p.trim().parse().map_err(|_| invalid())?;
+                if !(0.0..=100.0).contains(&p) {
+                    return Err(invalid());
+                }
+                Ok(Self::Percent(p))
+            }
+            None => s.parse().map(Self::Pixels).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Requested crop: `x,y,width,height`, or `width,height` placed by gravity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub position: Option<(Length, Length)>,
+    pub size: (Length, Length),
+}
+
+impl Crop {
+    /// Resolve against an image of `src` size, rejecting rectangles that
+    /// are empty or reach outside it
+    pub fn resolve(&self, src: (u32, u32), gravity: Gravity) -> Result<Rect, ConvertError> {
+        let (sw, sh) = (src.0 as f64, src.1 as f64);
+        let width = self.size.0.resolve(src.0);
+        let height = self.size.1.resolve(src.1);
+        let (x, y) = match self.position {
+            Some((x, y)) => (x.resolve(src.0), y.resolve(src.1)),
+            None => {
+                let (ax, ay) = gravity.anchor();
+                (
+                    (sw - width) * ax as f64 / 2.0,
+                    (sh - height) * ay as f64 / 2.0,
+                )
+            }
+        };
+
+        // Allow for rounding in percentages that add up to exactly 100
+        const EPSILON: f64 = 1e-6;
+        if width.round() < 1.0
+            || height.round() < 1.0
+            || x < 0.0
+            || y < 0.0
+            || x + width > sw + EPSILON
+            || y + height > sh + EPSILON
+        {
+            return Err(ConvertError::ValidationError(format!(
+                "Crop rectangle {}x{} at {},{} is outside the {}x{} image",
+                width.round(),
+                height.round(),
+                x.floor(),
+                y.floor(),
+                src.0,
+                src.1
+            )));
+        }
+
+        let x = (x.floor() as u32).min(src.0 - 1);
+        let y = (y.floor() as u32).min(src.1 - 1);
+        Ok(Rect {
+            x,
+            y,
+            width: (width.round() as u32).clamp(1, src.0 - x),
+            height: (height.round() as u32).clamp(1, src.1 - y),
+        })
+    }
+}
+
+impl FromStr for Crop {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Length>, _>>()?;
+        match values[..] {
+            [x, y, width, height] => Ok(Self {
+                position: Some((x, y)),
+                size: (width, height),
+            }),
+            [width, height] => Ok(Self {
+                position: None,
+                size: (width, height),
+            }),
+            _ => Err(ConvertError::ValidationError(format!(
+                "Invalid crop: {} (expected x,y,width,height or width,height)",
+                s
+            ))),
+        }
+    }
+}
+
+/// A pixel rectangle inside an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Map a rectangle on the displayed image onto pixels of `coded` size
+    /// that carry Exif `orientation`
+    pub fn to_coded(self, orientation: u16, coded: (u32, u32)) -> Rect {
+        let Rect {
+            x,
+            y,
+            width: w,
+            height: h,
+        } = self;
+        let (cw, ch) = coded;
+        let (x, y, w, h) = match orientation {
+            2 => (cw - x - w, y, w, h),
+            3 => (cw - x - w, ch - y - h, w, h),
+            4 => (x, ch - y - h, w, h),
+            5 => (y, x, h, w),
+            6 => (y, ch - x - w, h, w),
+            7 => (cw - y - h, ch - x - w, h, w),
+            8 => (cw - y - h, x, h, w),
+            _ => (x, y, w, h),
+        };
+        Rect {
+            x,
+            y,
+            width: w,
+            height: h,
+        }
+    }
+}
+
+/// Which part of the image is kept when cropping, or where it is placed
+/// when padding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Gravity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Center => "center",
+            Self::North => "north",
+            Self::NorthEast => "northeast",
+            Self::East => "east",
+            Self::SouthEast => "southeast",
+            Self::South => "south",
+            Self::SouthWest => "southwest",
+            Self::West => "west",
+            Self::NorthWest => "northwest",
+        }
+    }
+
+    /// Horizontal and vertical anchor in halves: 0 = left/top, 2 = right/bottom
+    pub fn anchor(self) -> (u8, u8) {
+        match self {
+            Self::Center => (1, 1),
+            Self::North => (1, 0),
+            Self::NorthEast => (2, 0),
+            Self::East => (2, 1),
+            Self::SouthEast => (2, 2),
+            Self::South => (1, 2),
+            Self::SouthWest => (0, 2),
+            Self::West => (0, 1),
+            Self::NorthWest => (0, 0),
+        }
+    }
+
+    fn from_anchor(anchor: (u8, u8)) -> Self {
+        match anchor {
+            (1, 0) => Self::North,
+            (2, 0) => Self::NorthEast,
+            (2, 1) => Self::East,
+            (2, 2) => Self::SouthEast,
+            (1, 2) => Self::South,
+            (0, 2) => Self::SouthWest,
+            (0, 1) => Self::West,
+            (0, 0) => Self::NorthWest,
+            _ => Self::Center,
+        }
+    }
+
+    /// The same gravity expressed on pixels that carry Exif `orientation`
+    pub fn to_coded(self, orientation: u16) -> Self {
+        let (ax, ay) = self.anchor();
+        Self::from_anchor(match orientation {
+            2 => (2 - ax, ay),
+            3 => (2 - ax, 2 - ay),
+            4 => (ax, 2 - ay),
+            5 => (ay, ax),
+            6 => (ay, 2 - ax),
+            7 => (2 - ay, 2 - ax),
+            8 => (2 - ay, ax),
+            _ => (ax, ay),
+        })
+    }
+}
+
+impl FromStr for Gravity {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "center" | "centre" => Ok(Self::Center),
+            "north" | "top" => Ok(Self::North),
+            "northeast" | "top-right" => Ok(Self::NorthEast),
+            "east" | "right" => Ok(Self::East),
+            "southeast" | "bottom-right" => Ok(Self::SouthEast),
+            "south" | "bottom" => Ok(Self::South),
+            "southwest" | "bottom-left" => Ok(Self::SouthWest),
+            "west" | "left" => Ok(Self::West),
+            "northwest" | "top-left" => Ok(Self::NorthWest),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid gravity: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Copy `rect` out of interleaved rows `stride` bytes apart into `out`
+pub fn crop(pixels: &[u8], stride: usize, channels: usize, rect: Rect, out: &mut Vec<u8>) {
+    let start = rect.x as usize * channels;
+    let len = rect.width as usize * channels;
+    out.clear();
+    out.reserve(len * rect.height as usize);
+    for row in pixels
+        .chunks(stride)
+        .skip(rect.y as usize)
+        .take(rect.height as usize)
+    {
+        out.extend_from_slice(&row[start..start + len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crop_parse_and_resolve() {
+        let crop: Crop = "10,20,100,50".parse().unwrap();
+        assert_eq!(
+            crop.resolve((400, 300), Gravity::Center).unwrap(),
+            Rect {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 50
+            }
+        );
+
+        let crop: Crop = "50%,50%,50%,50%".parse().unwrap();
+        assert_eq!(
+            crop.resolve((401, 301), Gravity::Center).unwrap(),
+            Rect {
+                x: 200,
+                y: 150,
+                width: 201,
+                height: 151
+            }
+        );
+
+        let crop: Crop = "200, 100".parse().unwrap();
+        assert_eq!(
+            crop.resolve((400, 300), Gravity::SouthEast).unwrap(),
+            Rect {
+                x: 200,
+                y: 200,
+                width: 200,
+                height: 100
+            }
+        );
+
+        assert!("1,2,3".parse::<Crop>().is_err());
+        assert!("120%,10".parse::<Crop>().is_err());
+    }
+
+    #[test]
+    fn test_crop_out_of_bounds() {
+        let crop: Crop = "300,0,200,100".parse().unwrap();
+        assert!(matches!(
+            crop.resolve((400, 300), Gravity::Center),
+            Err(ConvertError::ValidationError(_))
+        ));
+        let crop: Crop = "0,0".parse().unwrap();
+        assert!(crop.resolve((400, 300), Gravity::Center).is_err());
+        let crop: Crop = "500,100".parse().unwrap();
+        assert!(crop.resolve((400, 300), Gravity::Center).is_err());
+    }
+
+    #[test]
+    fn test_rect_to_coded() {
+        // Coded 4x2, displayed 2x4 after a 90° clockwise rotation
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 3,
+        };
+        assert_eq!(
+            rect.to_coded(6, (4, 2)),
+            Rect {
+                x: 0,
+                y: 1,
+                width: 3,
+                height: 1
+            }
+        );
+        assert_eq!(Gravity::West.to_coded(6), Gravity::South);
+        assert_eq!(Gravity::North.to_coded(3), Gravity::South);
+    }
+
+    #[test]
+    fn test_crop_copies_strided_rows() {
+        // 3x2 RGB with one byte of row padding
+        let pixels = [1, 1, 1, 2, 2, 2, 3, 3, 3, 0, 4, 4, 4, 5, 5, 5, 6, 6, 6, 0];
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 1,
+        };
+        let mut out = Vec::new();
+        crop(&pixels, 10, 3, rect, &mut out);
+        assert_eq!(out, vec![5, 5, 5, 6, 6, 6]);
+    }
+}
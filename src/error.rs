@@ -18,15 +18,34 @@ pub enum ConvertError {
     #[error("Invalid file: {0}")]
     ValidationError(String),
 
+    #[error("Unsupported input brand '{0}' (expected HEIC, HEIF or AVIF)")]
+    UnsupportedBrand(String),
+
     #[error("File too large: {size} bytes (max: {max} bytes)")]
     FileTooLarge { size: usize, max: usize },
 
     #[error("Image too large: {width}x{height} (max: {max}x{max})")]
     ImageTooLarge { width: u32, height: u32, max: u32 },
 
+    #[error("Image too large: {width}x{height} exceeds {max_megapixels} megapixels")]
+    TooManyPixels {
+        width: u32,
+        height: u32,
+        max_megapixels: u32,
+    },
+
+    #[error("Image needs about {required} bytes to convert (max: {max} bytes)")]
+    MemoryLimitExceeded { required: usize, max: usize },
+
+    #[error("File exceeds a decoder security limit: {0}")]
+    SecurityLimitExceeded(String),
+
     #[error("Invalid quality: {0} (must be 60-95)")]
     InvalidQuality(u8),
 
+    #[error("Output cannot fit in {max_bytes} bytes (smallest attempt: {smallest} bytes)")]
+    TargetSizeUnreachable { max_bytes: usize, smallest: usize },
+
     #[error("Queue full, try again later")]
     QueueFull,
 
@@ -44,9 +63,22 @@ impl IntoResponse for ConvertError {
             ConvertError::DecodeError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             ConvertError::EncodeError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
             ConvertError::ValidationError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            ConvertError::UnsupportedBrand(_) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, self.to_string())
+            }
             ConvertError::FileTooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()),
             ConvertError::ImageTooLarge { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
+            ConvertError::TooManyPixels { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
+            ConvertError::MemoryLimitExceeded { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string())
+            }
+            ConvertError::SecurityLimitExceeded(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string())
+            }
             ConvertError::InvalidQuality(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            ConvertError::TargetSizeUnreachable { .. } => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string())
+            }
             ConvertError::QueueFull => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
             ConvertError::Timeout => (StatusCode::GATEWAY_TIMEOUT, self.to_string()),
             ConvertError::Internal(_) => (
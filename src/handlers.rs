@@ -1,18 +1,37 @@
 //! HTTP handlers for the HEIC to JPG converter API
 
+use crate::buffers;
+use crate::color;
+use crate::config::Config;
+use crate::converter::{self, ConvertOptions, ImageSelection};
 use crate::error::ConvertError;
+use crate::limits::Limits;
+use crate::probe;
+use crate::resize::FitMode;
 use crate::state::AppState;
 use axum::{
-    extract::{Multipart, State},
-    http::{header, StatusCode},
+    extract::{multipart::Field, Multipart, State},
+    http::{header, HeaderName, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use chrono::Utc;
 use std::sync::Arc;
-use tracing::{error, info, instrument};
+use tracing::{info, instrument};
 // use uuid::Uuid;
 
+/// Response header reporting which orientation mode was applied
+const ORIENTATION_HEADER: &str = "x-orientation";
+
+/// Response header carrying the quality the output was encoded with
+const QUALITY_HEADER: &str = "x-quality";
+
+/// Default bounding box for `/api/thumbnail`, in pixels
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+/// Largest bounding box `/api/thumbnail` accepts, in pixels
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
 /// Health check endpoint
 pub async fn health() -> impl IntoResponse {
     Json(serde_json::json!({
@@ -21,11 +40,126 @@ pub async fn health() -> impl IntoResponse {
     }))
 }
 
+/// Buffer pool counters, to verify allocation savings
+pub async fn stats() -> impl IntoResponse {
+    Json(buffers::stats())
+}
+
+/// Read a multipart field as text
+async fn read_text(field: Field<'_>) -> Result<String, ConvertError> {
+    field
+        .text()
+        .await
+        .map_err(|e| ConvertError::ValidationError(e.to_string()))
+}
+
+/// Read the uploaded file, enforcing the configured size limit
+async fn read_file(field: Field<'_>, config: &Config) -> Result<Vec<u8>, ConvertError> {
+    let data = field
+        .bytes()
+        .await
+        .map_err(|e| ConvertError::ValidationError(e.to_string()))?;
+
+    // Check file size
+    if data.len() > config.max_file_size {
+        return Err(ConvertError::FileTooLarge {
+            size: data.len(),
+            max: config.max_file_size,
+        });
+    }
+
+    Ok(data.to_vec())
+}
+
+/// Parse and range-check a quality value
+fn parse_quality(value: &str, config: &Config) -> Result<u8, ConvertError> {
+    let quality = value
+        .parse::<u8>()
+        .map_err(|_| ConvertError::ValidationError("Invalid quality value".to_string()))?;
+
+    // Validate quality range
+    if quality < config.min_quality || quality > config.max_quality {
+        return Err(ConvertError::InvalidQuality(quality));
+    }
+
+    Ok(quality)
+}
+
+/// Parse a boolean form value (`true`/`false`, `1`/`0`, `on`/`off`)
+fn parse_bool(name: &str, value: &str) -> Result<bool, ConvertError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "on" | "yes" => Ok(true),
+        "false" | "0" | "off" | "no" => Ok(false),
+        _ => Err(ConvertError::ValidationError(format!(
+            "Invalid {} value",
+            name
+        ))),
+    }
+}
+
+/// Parse a target output size in bytes
+fn parse_max_bytes(value: &str) -> Result<usize, ConvertError> {
+    match value.trim().parse::<usize>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(ConvertError::ValidationError(
+            "Invalid max_bytes value (must be a positive integer)".to_string(),
+        )),
+    }
+}
+
+/// Parse an AVIF encoder speed (0-9)
+fn parse_speed(value: &str) -> Result<u8, ConvertError> {
+    match value.trim().parse::<u8>() {
+        Ok(v) if v <= 9 => Ok(v),
+        _ => Err(ConvertError::ValidationError(
+            "Invalid speed value (must be 0-9)".to_string(),
+        )),
+    }
+}
+
+/// Parse a target dimension, bounded by the configured maximum resolution
+fn parse_dimension(name: &str, value: &str, max: u32) -> Result<u32, ConvertError> {
+    match value.trim().parse::<u32>() {
+        Ok(v) if (1..=max).contains(&v) => Ok(v),
+        _ => Err(ConvertError::ValidationError(format!(
+            "Invalid {} value (must be 1-{})",
+            name, max
+        ))),
+    }
+}
+
 /// Convert HEIC to JPG endpoint
 ///
 /// Accepts multipart form data with:
-/// - `file`: HEIC file (required)
-/// - `quality`: JPEG quality 60-95 (optional, default 85)
+/// - `file`: HEIC, HEIF, AVIF, JPEG, PNG or WebP file (required)
+/// - `format`: `jpeg`, `png`, `webp` or `avif` (optional, default jpeg)
+/// - `quality`: JPEG/WebP/AVIF quality 60-95 (optional, default 85)
+/// - `lossless`: lossless WebP or AVIF (optional, default false)
+/// - `speed`: AVIF encoder speed 0-9 (optional, default 6)
+/// - `progressive`: progressive JPEG scans (optional, default false)
+/// - `optimize`: optimized JPEG Huffman tables (optional, default false)
+/// - `subsampling`: `444`, `422`, `420`, `440`, `gray` or `auto` (optional, default 420)
+/// - `max_bytes`: pick the highest quality that fits this size (optional)
+/// - `downscale`: shrink the image if `max_bytes` is unreachable (optional, default false)
+/// - `keep_metadata`: copy Exif, including GPS, into the output (optional, default false)
+/// - `to_srgb`: convert wide-gamut pixels to sRGB (optional, default false)
+/// - `rendering_intent`: intent used by `to_srgb` (optional, default perceptual)
+/// - `orientation`: `apply` or `preserve` (optional, default apply)
+/// - `tone_map`: `clip`, `reinhard`, `hable` or `aces` for HDR input (optional, default reinhard)
+/// - `crop`: `x,y,width,height` or `width,height`, each in px or `%` (optional)
+/// - `gravity`: `center`, `north`, `northeast`, ... anchor for crop and fit (optional, default center)
+/// - `width` / `height`: target size in pixels (optional)
+/// - `fit`: `contain`, `cover`, `fill` or `inside` (optional, default inside)
+/// - `background`: `#RRGGBB` colour for transparency and padding (optional, default white)
+/// - `images`: `primary` or `all` top-level images (optional, default primary)
+/// - `index`: convert the N-th top-level image, 0-based (optional)
+/// - `layers`: export depth/auxiliary layers as `jpeg` or `png` (optional)
+///
+/// Content-Type and filename extension follow `format`. With `images=all`
+/// or `layers` the response is a ZIP archive of `image_NNN.<ext>` files,
+/// their layers and a `manifest.json`.
+/// The orientation mode used is reported in the `X-Orientation` header and
+/// the encoding quality in `X-Quality`.
 #[instrument(skip(state, multipart))]
 pub async fn convert_handler(
     State(state): State<Arc<AppState>>,
@@ -34,6 +168,9 @@ pub async fn convert_handler(
     let mut file_data: Option<Vec<u8>> = None;
     let mut file_name: Option<String> = None;
     let mut quality: u8 = state.config.default_quality;
+    let mut options = ConvertOptions::from_config(&state.config);
+    let mut all_images = false;
+    let mut image_index: Option<usize> = None;
 
     // Parse multipart form
     while let Some(field) = multipart
@@ -46,35 +183,89 @@ pub async fn convert_handler(
         match name.as_str() {
             "file" => {
                 file_name = field.file_name().map(|s| s.to_string());
-                let data = field
-                    .bytes()
-                    .await
-                    .map_err(|e| ConvertError::ValidationError(e.to_string()))?;
-
-                // Check file size
-                if data.len() > state.config.max_file_size {
-                    return Err(ConvertError::FileTooLarge {
-                        size: data.len(),
-                        max: state.config.max_file_size,
-                    });
-                }
-
-                file_data = Some(data.to_vec());
+                file_data = Some(read_file(field, &state.config).await?);
+            }
+            "format" => {
+                options.format = read_text(field).await?.parse()?;
+            }
+            "lossless" => {
+                options.lossless = parse_bool(&name, &read_text(field).await?)?;
+            }
+            "speed" => {
+                options.speed = parse_speed(&read_text(field).await?)?;
+            }
+            "progressive" => {
+                options.progressive = parse_bool(&name, &read_text(field).await?)?;
+            }
+            "optimize" => {
+                options.optimize = parse_bool(&name, &read_text(field).await?)?;
+            }
+            "subsampling" => {
+                options.subsampling = read_text(field).await?.parse()?;
+            }
+            "max_bytes" => {
+                options.max_bytes = Some(parse_max_bytes(&read_text(field).await?)?);
+            }
+            "downscale" => {
+                options.downscale_fallback = parse_bool(&name, &read_text(field).await?)?;
             }
             "quality" => {
-                let q_str = field
-                    .text()
-                    .await
-                    .map_err(|e| ConvertError::ValidationError(e.to_string()))?;
-
-                quality = q_str.parse::<u8>().map_err(|_| {
-                    ConvertError::ValidationError("Invalid quality value".to_string())
-                })?;
-
-                // Validate quality range
-                if quality < state.config.min_quality || quality > state.config.max_quality {
-                    return Err(ConvertError::InvalidQuality(quality));
-                }
+                quality = parse_quality(&read_text(field).await?, &state.config)?;
+            }
+            "keep_metadata" => {
+                options.keep_metadata = parse_bool(&name, &read_text(field).await?)?;
+            }
+            "to_srgb" => {
+                options.to_srgb = parse_bool(&name, &read_text(field).await?)?;
+            }
+            "rendering_intent" => {
+                options.rendering_intent = read_text(field).await?.parse()?;
+            }
+            "orientation" => {
+                options.orientation = read_text(field).await?.parse()?;
+            }
+            "tone_map" => {
+                options.tone_map = read_text(field).await?.parse()?;
+            }
+            "crop" => {
+                options.crop = Some(read_text(field).await?.parse()?);
+            }
+            "gravity" => {
+                options.gravity = read_text(field).await?.parse()?;
+            }
+            "width" => {
+                let value = read_text(field).await?;
+                options.width = Some(parse_dimension(&name, &value, state.config.max_resolution)?);
+            }
+            "height" => {
+                let value = read_text(field).await?;
+                options.height = Some(parse_dimension(&name, &value, state.config.max_resolution)?);
+            }
+            "fit" => {
+                options.fit = read_text(field).await?.parse()?;
+            }
+            "background" => {
+                options.background = color::parse_hex_color(&read_text(field).await?)?;
+            }
+            "images" => {
+                all_images = match read_text(field).await?.trim() {
+                    "primary" => false,
+                    "all" => true,
+                    _ => {
+                        return Err(ConvertError::ValidationError(
+                            "Invalid images value (must be primary or all)".to_string(),
+                        ))
+                    }
+                };
+            }
+            "layers" => {
+                options.layers = Some(read_text(field).await?.parse()?);
+            }
+            "index" => {
+                let value = read_text(field).await?;
+                image_index = Some(value.trim().parse().map_err(|_| {
+                    ConvertError::ValidationError("Invalid index value".to_string())
+                })?);
             }
             _ => {
                 // Ignore unknown fields
@@ -86,10 +277,44 @@ pub async fn convert_handler(
     let file_data = file_data
         .ok_or_else(|| ConvertError::ValidationError("Missing 'file' field".to_string()))?;
 
+    options.images = match (all_images, image_index) {
+        (true, Some(_)) => {
+            return Err(ConvertError::ValidationError(
+                "'images=all' and 'index' are mutually exclusive".to_string(),
+            ))
+        }
+        (true, None) => ImageSelection::All,
+        (false, Some(index)) => ImageSelection::Index(index),
+        (false, None) => ImageSelection::Primary,
+    };
+
+    // Reject unsupported inputs before they take a queue slot
+    let input = converter::sniff_input(&file_data)?;
+
     info!(
         file_name = ?file_name,
         size = file_data.len(),
+        input = input.as_str(),
+        format = options.format.as_str(),
+        lossless = options.lossless,
+        speed = options.speed,
+        progressive = options.progressive,
+        optimize = options.optimize,
+        subsampling = options.subsampling.as_str(),
+        max_bytes = ?options.max_bytes,
+        downscale = options.downscale_fallback,
         quality = quality,
+        keep_metadata = options.keep_metadata,
+        to_srgb = options.to_srgb,
+        orientation = options.orientation.as_str(),
+        tone_map = options.tone_map.as_str(),
+        crop = ?options.crop,
+        gravity = options.gravity.as_str(),
+        width = ?options.width,
+        height = ?options.height,
+        background = ?options.background,
+        images = ?options.images,
+        layers = ?options.layers,
         "Processing conversion request"
     );
 
@@ -117,51 +342,316 @@ pub async fn convert_handler(
     //     info!(path = ?upload_path, "File saved for audit");
     // }
 
+    let orientation = options.orientation;
+
     // Submit to worker pool
-    let result_rx = state.worker_pool.submit(file_data, quality).await?;
+    let result_rx = state
+        .worker_pool
+        .submit(file_data, quality, options)
+        .await?;
 
     // Wait for result
-    let jpeg_data = result_rx
+    let output = result_rx
         .await
         .map_err(|_| ConvertError::Internal("Worker dropped".to_string()))??;
 
     // Generate output filename
     // User requested "just numbers". Using millisecond timestamp ensures numeric, unique, and ordered.
-    let output_name = format!("{}.jpg", Utc::now().timestamp_millis());
+    let output_name = format!("{}.{}", Utc::now().timestamp_millis(), output.extension);
 
-    info!(output_name = %output_name, size = jpeg_data.len(), "Conversion complete");
+    info!(
+        output_name = %output_name,
+        size = output.data.len(),
+        quality = output.quality,
+        "Conversion complete"
+    );
 
     // Build response with correct headers
     Ok((
         StatusCode::OK,
         [
-            (header::CONTENT_TYPE, "image/jpeg"),
+            (header::CONTENT_TYPE, output.content_type),
             (
                 header::CONTENT_DISPOSITION,
                 &format!("attachment; filename=\"{}\"", output_name),
             ),
+            (
+                HeaderName::from_static(ORIENTATION_HEADER),
+                orientation.as_str(),
+            ),
+            (
+                HeaderName::from_static(QUALITY_HEADER),
+                &output.quality.to_string(),
+            ),
         ],
-        jpeg_data,
+        output.data,
     )
         .into_response())
 }
 
-/// Batch convert endpoint info
-pub async fn batch_info(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+/// Thumbnail endpoint
+///
+/// Accepts multipart form data with:
+/// - `file`: HEIC file (required)
+/// - `size`: bounding box for the longer side in pixels (optional, default 256)
+/// - `quality`: JPEG quality 60-95 (optional, default 85)
+///
+/// Uses the embedded HEIF thumbnail when one covers `size` and falls back to
+/// a scaled decode of the primary image otherwise. Runs on the shared worker
+/// pool, so it is subject to the same queue limits as `/api/convert`.
+#[instrument(skip(state, multipart))]
+pub async fn thumbnail_handler(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Response, ConvertError> {
+    let mut file_data: Option<Vec<u8>> = None;
+    let mut quality: u8 = state.config.default_quality;
+    let mut size = DEFAULT_THUMBNAIL_SIZE;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ConvertError::ValidationError(e.to_string()))?
+    {
+        let name = field.name().unwrap_or("").to_string();
+
+        match name.as_str() {
+            "file" => file_data = Some(read_file(field, &state.config).await?),
+            "quality" => quality = parse_quality(&read_text(field).await?, &state.config)?,
+            "size" => {
+                let max = MAX_THUMBNAIL_SIZE.min(state.config.max_resolution);
+                size = parse_dimension(&name, &read_text(field).await?, max)?;
+            }
+            _ => {
+                // Ignore unknown fields
+            }
+        }
+    }
+
+    let file_data = file_data
+        .ok_or_else(|| ConvertError::ValidationError("Missing 'file' field".to_string()))?;
+
+    info!(
+        size = file_data.len(),
+        thumbnail_size = size,
+        "Processing thumbnail request"
+    );
+
+    // Thumbnails are previews: no Exif, bounded to a square box
+    let mut options = ConvertOptions::from_config(&state.config);
+    options.keep_metadata = false;
+    options.thumbnail = Some(size);
+    options.width = Some(size);
+    options.height = Some(size);
+    options.fit = FitMode::Inside;
+
+    let result_rx = state
+        .worker_pool
+        .submit(file_data, quality, options)
+        .await?;
+    let output = result_rx
+        .await
+        .map_err(|_| ConvertError::Internal("Worker dropped".to_string()))??;
+
+    let output_name = format!(
+        "{}_thumb.{}",
+        Utc::now().timestamp_millis(),
+        output.extension
+    );
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, output.content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                &format!("inline; filename=\"{}\"", output_name),
+            ),
+        ],
+        output.data,
+    )
+        .into_response())
+}
+
+/// Probe endpoint
+///
+/// Accepts multipart form data with:
+/// - `file`: HEIC file (required)
+///
+/// Returns the container structure as JSON without decoding any pixels.
+/// Applies the same file size and resolution limits as `/api/convert`.
+#[instrument(skip(state, multipart))]
+pub async fn probe_handler(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Response, ConvertError> {
+    let mut file_data: Option<Vec<u8>> = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ConvertError::ValidationError(e.to_string()))?
+    {
+        if field.name() == Some("file") {
+            file_data = Some(read_file(field, &state.config).await?);
+        }
+    }
+
+    let file_data = file_data
+        .ok_or_else(|| ConvertError::ValidationError("Missing 'file' field".to_string()))?;
+
+    info!(size = file_data.len(), "Processing probe request");
+
+    let limits = Limits::from_config(&state.config);
+    let info = tokio::task::spawn_blocking(move || probe::probe(&file_data, &limits))
+        .await
+        .map_err(|e| ConvertError::Internal(e.to_string()))??;
+    Ok(Json(info).into_response())
+}
+
+/// Probe endpoint info
+pub async fn probe_info() -> impl IntoResponse {
     Json(serde_json::json!({
-        "endpoint": "/api/convert",
+        "endpoint": "/api/probe",
+        "method": "POST",
+        "description": "Inspect a HEIC file without decoding it",
+        "fields": {
+            "file": "HEIC file (required)"
+        },
+        "response": {
+            "format": "Input format detected from the ftyp brands: heic, heif or avif",
+            "brand": "Major ftyp brand, e.g. heic",
+            "compatible_brands": "Compatible ftyp brands",
+            "image_count": "Number of top-level images",
+            "images": "Per image: index, primary, width, height, bit_depth, chroma_bit_depth, has_alpha, thumbnails ([width, height] pairs), has_depth, auxiliary_count, metadata (block types such as Exif or mime), color_profile (icc, nclx or null), memory (estimated bytes to convert)"
+        }
+    }))
+}
+
+/// Thumbnail endpoint info
+pub async fn thumbnail_info(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "endpoint": "/api/thumbnail",
         "method": "POST",
-        "description": "Convert HEIC to JPG",
+        "description": "Fast HEIC preview from the embedded thumbnail",
         "fields": {
             "file": "HEIC file (required)",
+            "size": format!("Bounding box 1-{} px (optional, default {})",
+                MAX_THUMBNAIL_SIZE.min(state.config.max_resolution),
+                DEFAULT_THUMBNAIL_SIZE),
             "quality": format!("JPEG quality {}-{} (optional, default {})",
                 state.config.min_quality,
                 state.config.max_quality,
                 state.config.default_quality)
+        }
+    }))
+}
+
+/// Batch convert endpoint info
+pub async fn batch_info(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "endpoint": "/api/convert",
+        "method": "POST",
+        "description": "Convert HEIC to JPG, PNG, WebP or AVIF",
+        "fields": {
+            "file": "HEIC, HEIF or AVIF still image, or a JPEG, PNG or WebP that goes through the same pipeline (required); other ftyp brands are rejected with 415",
+            "format": "Output format: jpeg, png, webp or avif (optional, default jpeg)",
+            "lossless": "Lossless WebP/AVIF: true/false (optional, default false)",
+            "speed": "AVIF encoder speed 0 (smallest) to 9 (fastest) (optional, default 6)",
+            "progressive": "Progressive JPEG: true/false. Usually 2-10% smaller and renders coarse-to-fine while loading; the baseline output is re-coded losslessly into progressive scans, roughly doubling encode time (optional, default false)",
+            "optimize": "Optimized JPEG Huffman tables: true/false. Typically 3-7% smaller for about 10-20% more encode time; implied by progressive (optional, default false)",
+            "subsampling": "JPEG chroma subsampling: 444, 422, 420, 440, gray, or auto (444 from quality 90, else 420) (optional, default 420)",
+            "max_bytes": "Target output size in bytes; the highest quality that fits is used and reported in X-Quality (optional)",
+            "downscale": "With max_bytes, shrink the image when even the minimum quality is too large: true/false (optional, default false)",
+            "quality": format!("JPEG/WebP/AVIF quality {}-{} (optional, default {})",
+                state.config.min_quality,
+                state.config.max_quality,
+                state.config.default_quality),
+            "keep_metadata": "Copy Exif metadata (capture time, camera, GPS location) into the output: true/false (optional, default false, so location data is stripped)",
+            "to_srgb": "Convert wide-gamut (Display P3, BT.2020) pixels to sRGB: true/false (optional, default false)",
+            "rendering_intent": "perceptual, relative, saturation or absolute (optional, default perceptual)",
+            "orientation": "apply (rotate pixels, Exif Orientation=1) or preserve (keep coded pixels, set Exif Orientation) (optional, default apply)",
+            "tone_map": "Tone-mapping operator for HDR (PQ/HLG) input: clip, reinhard, hable or aces (optional, default reinhard)",
+            "crop": "Region to cut out before resizing, in display orientation: x,y,width,height or width,height (placed by gravity); each value in px or % such as 10%. Rectangles outside the image are rejected (optional)",
+            "gravity": "center, north, northeast, east, southeast, south, southwest, west or northwest: where a crop without x,y is taken from, the part kept by fit=cover and the placement for fit=contain (optional, default center)",
+            "width": format!("Target width 1-{} px; the output may not exceed {} megapixels (optional)", state.config.max_resolution, state.config.max_megapixels),
+            "height": format!("Target height 1-{} px; the output may not exceed {} megapixels (optional)", state.config.max_resolution, state.config.max_megapixels),
+            "fit": "contain, cover, fill or inside when both width and height are set (optional, default inside)",
+            "background": "#RRGGBB colour that transparent pixels and contain padding are flattened onto (optional, default #ffffff)",
+            "images": "primary, or all to convert every top-level image into a ZIP (optional, default primary)",
+            "index": "0-based top-level image to convert (optional)",
+            "layers": "Export depth maps and auxiliary images (mattes, gain maps) as jpeg or png in a ZIP with manifest.json (optional)"
         },
         "limits": {
             "max_file_size": format!("{}MB", state.config.max_file_size / 1024 / 1024),
-            "max_resolution": format!("{}x{}", state.config.max_resolution, state.config.max_resolution)
+            "max_resolution": format!("{}x{}", state.config.max_resolution, state.config.max_resolution),
+            "max_megapixels": state.config.max_megapixels,
+            "max_job_memory": format!("{}MB", state.config.max_job_memory / 1024 / 1024)
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::create_router;
+    use crate::worker::WorkerPool;
+    use axum::body::{self, Body};
+    use axum::http::Request;
+    use image::codecs::png::PngEncoder;
+    use image::{ExtendedColorType, ImageEncoder};
+    use tower::ServiceExt;
+
+    /// Multipart body with a `file` part followed by text fields
+    fn multipart_body(boundary: &str, file: &[u8], fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"in.png\"\r\nContent-Type: image/png\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}"
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn test_convert_rejects_upscale_past_megapixel_limit() {
+        let config = Config::from_env();
+        let state = Arc::new(AppState {
+            worker_pool: WorkerPool::new(&config),
+            config: Arc::new(config),
+        });
+
+        // A 1x1 upload stretched to the full side limit is 268 megapixels
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&[0, 0, 0], 1, 1, ExtendedColorType::Rgb8)
+            .unwrap();
+        let body = multipart_body(
+            "boundary",
+            &png,
+            &[("width", "16384"), ("height", "16384"), ("fit", "fill")],
+        );
+        let request = Request::post("/api/convert")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("megapixels"));
+    }
+}
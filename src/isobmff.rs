@@ -0,0 +1,336 @@
+//! Minimal ISO-BMFF box reader for HEIF container properties
+//!
+//! libheif does not expose everything we need (e.g. the `irot`/`imir`
+//! transformations of an item), so these are read straight from the boxes.
+//! Only the handful of boxes involved are parsed; pixels never pass through here.
+
+/// Iterate over the boxes in `data`, yielding `(type, payload)` pairs
+///
+/// Stops at the first malformed box header.
+fn boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as u64;
+        let typ: [u8; 4] = rest.get(4..8)?.try_into().ok()?;
+        let (header_len, size) = match size {
+            0 => (8, rest.len() as u64),
+            1 => (16, u64::from_be_bytes(rest.get(8..16)?.try_into().ok()?)),
+            n => (8, n),
+        };
+        let size = usize::try_from(size).ok()?;
+        if size < header_len || size > rest.len() {
+            return None;
+        }
+        let payload = &rest[header_len..size];
+        rest = &rest[size..];
+        Some((typ, payload))
+    })
+}
+
+/// Find the first child box of the given type
+fn find_box<'a>(data: &'a [u8], typ: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(t, _)| t == typ)
+        .map(|(_, payload)| payload)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// Brands declared in the `ftyp` box at the start of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileType {
+    pub major_brand: [u8; 4],
+    pub compatible_brands: Vec<[u8; 4]>,
+}
+
+/// Read the leading `ftyp` box
+///
+/// Returns `None` if the file does not start with one.
+pub fn file_type(data: &[u8]) -> Option<FileType> {
+    let (typ, payload) = boxes(data).next()?;
+    if &typ != b"ftyp" {
+        return None;
+    }
+    // Major brand, minor version, then compatible brands
+    let major_brand = payload.get(..4)?.try_into().ok()?;
+    let compatible_brands = payload
+        .get(8..)?
+        .chunks_exact(4)
+        .filter_map(|brand| brand.try_into().ok())
+        .collect();
+    Some(FileType {
+        major_brand,
+        compatible_brands,
+    })
+}
+
+impl FileType {
+    /// Still-image format declared by the brands
+    ///
+    /// Codec brands take precedence over the generic `mif1`/`mif2`, wherever
+    /// they appear. Sequence-only brands (`msf1`, `hevc`, `avis`) have no
+    /// still image for libheif to decode and are not recognised.
+    pub fn heif_format(&self) -> Option<HeifFormat> {
+        let brands = || std::iter::once(&self.major_brand).chain(&self.compatible_brands);
+        brands()
+            .find_map(|brand| match brand {
+                b"heic" | b"heix" | b"heim" | b"heis" => Some(HeifFormat::Heic),
+                b"avif" => Some(HeifFormat::Avif),
+                _ => None,
+            })
+            .or_else(|| {
+                brands()
+                    .any(|brand| matches!(brand, b"mif1" | b"mif2"))
+                    .then_some(HeifFormat::Heif)
+            })
+    }
+}
+
+/// HEIF-family still-image formats accepted as input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeifFormat {
+    /// HEVC-coded HEIF (`.heic`)
+    Heic,
+    /// HEIF with another codec, or only the generic structural brand
+    Heif,
+    /// AV1-coded HEIF (`.avif`)
+    Avif,
+}
+
+impl HeifFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Heic => "heic",
+            Self::Heif => "heif",
+            Self::Avif => "avif",
+        }
+    }
+}
+
+/// Printable form of a four-character code
+pub fn fourcc(code: &[u8; 4]) -> String {
+    code.iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+/// A geometric transformation attached to an image item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transform {
+    /// Anti-clockwise rotation in 90° steps (`irot`)
+    Rotate(u8),
+    /// Mirror about the vertical (`false`) or horizontal (`true`) axis (`imir`)
+    Mirror(bool),
+}
+
+/// Read the `irot`/`imir` transformations of an item, in application order
+fn item_transforms(data: &[u8], item_id: u32) -> Option<Vec<Transform>> {
+    let meta = find_box(data, b"meta")?;
+    // `meta` is a full box: skip version and flags
+    let iprp = find_box(meta.get(4..)?, b"iprp")?;
+    let properties: Vec<([u8; 4], &[u8])> = boxes(find_box(iprp, b"ipco")?).collect();
+    let ipma = find_box(iprp, b"ipma")?;
+
+    let version = *ipma.first()?;
+    let large_index = ipma.get(3)? & 1 == 1;
+    let entry_count = read_u32(ipma, 4)?;
+    let mut pos = 8;
+
+    for _ in 0..entry_count {
+        let id = if version < 1 {
+            let id = read_u16(ipma, pos)? as u32;
+            pos += 2;
+            id
+        } else {
+            let id = read_u32(ipma, pos)?;
+            pos += 4;
+            id
+        };
+        let association_count = *ipma.get(pos)? as usize;
+        pos += 1;
+
+        let mut indices = Vec::with_capacity(association_count);
+        for _ in 0..association_count {
+            let index = if large_index {
+                let index = read_u16(ipma, pos)? & 0x7FFF;
+                pos += 2;
+                index
+            } else {
+                let index = (*ipma.get(pos)? & 0x7F) as u16;
+                pos += 1;
+                index
+            };
+            indices.push(index);
+        }
+
+        if id != item_id {
+            continue;
+        }
+
+        // Property indices are 1-based; 0 means "no property"
+        let transforms = indices
+            .into_iter()
+            .filter_map(|index| properties.get((index as usize).checked_sub(1)?))
+            .filter_map(|(typ, payload)| match typ {
+                b"irot" => Some(Transform::Rotate(payload.first()? & 0x03)),
+                b"imir" => Some(Transform::Mirror(payload.first()? & 0x01 == 1)),
+                _ => None,
+            })
+            .collect();
+        return Some(transforms);
+    }
+
+    Some(Vec::new())
+}
+
+/// Exif Orientation value equivalent to an item's `irot`/`imir` transformations
+///
+/// Returns 1 (no transformation) if the item has none or the boxes cannot be read.
+pub fn item_exif_orientation(data: &[u8], item_id: u32) -> u16 {
+    item_transforms(data, item_id)
+        .map(|transforms| exif_orientation(&transforms))
+        .unwrap_or(1)
+}
+
+/// Collapse a sequence of transformations into an Exif Orientation value
+fn exif_orientation(transforms: &[Transform]) -> u16 {
+    // Track the transformation as a 2x2 matrix on (x, y) with y pointing down
+    let mut m = [[1i8, 0], [0, 1]];
+    for transform in transforms {
+        let t = match *transform {
+            Transform::Rotate(steps) => match steps {
+                1 => [[0, 1], [-1, 0]],
+                2 => [[-1, 0], [0, -1]],
+                3 => [[0, -1], [1, 0]],
+                _ => [[1, 0], [0, 1]],
+            },
+            Transform::Mirror(false) => [[-1, 0], [0, 1]],
+            Transform::Mirror(true) => [[1, 0], [0, -1]],
+        };
+        m = [
+            [
+                t[0][0] * m[0][0] + t[0][1] * m[1][0],
+                t[0][0] * m[0][1] + t[0][1] * m[1][1],
+            ],
+            [
+                t[1][0] * m[0][0] + t[1][1] * m[1][0],
+                t[1][0] * m[0][1] + t[1][1] * m[1][1],
+            ],
+        ];
+    }
+
+    match m {
+        [[-1, 0], [0, 1]] => 2,
+        [[-1, 0], [0, -1]] => 3,
+        [[1, 0], [0, -1]] => 4,
+        [[0, 1], [1, 0]] => 5,
+        [[0, -1], [1, 0]] => 6,
+        [[0, -1], [-1, 0]] => 7,
+        [[0, 1], [-1, 0]] => 8,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(typ: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(typ);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        assert_eq!(exif_orientation(&[]), 1);
+        assert_eq!(exif_orientation(&[Transform::Rotate(1)]), 8);
+        assert_eq!(exif_orientation(&[Transform::Rotate(2)]), 3);
+        assert_eq!(exif_orientation(&[Transform::Rotate(3)]), 6);
+        assert_eq!(exif_orientation(&[Transform::Mirror(false)]), 2);
+        assert_eq!(exif_orientation(&[Transform::Mirror(true)]), 4);
+        assert_eq!(
+            exif_orientation(&[Transform::Rotate(1), Transform::Mirror(false)]),
+            7
+        );
+        assert_eq!(
+            exif_orientation(&[Transform::Rotate(3), Transform::Mirror(false)]),
+            5
+        );
+    }
+
+    #[test]
+    fn test_item_exif_orientation() {
+        let ipco = make_box(
+            b"ipco",
+            &[make_box(b"ispe", &[0; 12]), make_box(b"irot", &[3])].concat(),
+        );
+        // version 0, flags 0, one entry: item 1 -> properties 1 and 2
+        let ipma = make_box(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 2, 0x81, 0x02]);
+        let iprp = make_box(b"iprp", &[ipco, ipma].concat());
+        let meta = make_box(b"meta", &[&[0, 0, 0, 0][..], &iprp].concat());
+        let file = [make_box(b"ftyp", b"heic\0\0\0\0mif1heic"), meta].concat();
+
+        assert_eq!(item_exif_orientation(&file, 1), 6);
+        assert_eq!(item_exif_orientation(&file, 2), 1);
+        assert_eq!(item_exif_orientation(b"garbage", 1), 1);
+    }
+
+    #[test]
+    fn test_heif_format() {
+        let ftyp = |major: &[u8; 4], compatible: &[&[u8; 4]]| FileType {
+            major_brand: *major,
+            compatible_brands: compatible.iter().map(|b| **b).collect(),
+        };
+        assert_eq!(
+            ftyp(b"mif1", &[b"mif1", b"heic"]).heif_format(),
+            Some(HeifFormat::Heic)
+        );
+        assert_eq!(
+            ftyp(b"avif", &[b"mif1", b"miaf"]).heif_format(),
+            Some(HeifFormat::Avif)
+        );
+        assert_eq!(
+            ftyp(b"mif1", &[b"mif1", b"miaf"]).heif_format(),
+            Some(HeifFormat::Heif)
+        );
+        // A sequence with a still image is converted from the still image
+        assert_eq!(
+            ftyp(b"msf1", &[b"msf1", b"mif1", b"heic", b"hevc"]).heif_format(),
+            Some(HeifFormat::Heic)
+        );
+        assert_eq!(ftyp(b"msf1", &[b"msf1", b"hevc"]).heif_format(), None);
+        assert_eq!(ftyp(b"isom", &[b"mp42"]).heif_format(), None);
+    }
+
+    #[test]
+    fn test_file_type() {
+        let file = [
+            make_box(b"ftyp", b"heic\0\0\0\0mif1heic"),
+            make_box(b"meta", &[]),
+        ]
+        .concat();
+        let ftyp = file_type(&file).unwrap();
+        assert_eq!(&ftyp.major_brand, b"heic");
+        assert_eq!(ftyp.compatible_brands, vec![*b"mif1", *b"heic"]);
+        assert_eq!(fourcc(b"av\x01f"), "av?f");
+
+        assert_eq!(ftyp.heif_format(), Some(HeifFormat::Heic));
+
+        assert_eq!(file_type(&make_box(b"meta", &[])), None);
+        assert_eq!(file_type(b"\xFF\xD8\xFF\xE0"), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! Depth maps and auxiliary images
+//!
+//! Portrait-mode photos carry extra monochrome layers next to the primary
+//! image: depth maps, segmentation mattes and HDR gain maps. They are decoded
+//! as grayscale and exported as separate files.
+
+use crate::error::ConvertError;
+use crate::limits::{self, Limits};
+use libheif_rs::{AuxiliaryImagesFilter, ColorSpace, ImageHandle, ItemId, LibHeif};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// File format for exported layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerFormat {
+    /// 8-bit grayscale JPEG
+    Jpeg,
+    /// Grayscale PNG, keeping 16-bit precision for high bit depth layers
+    Png,
+}
+
+impl LayerFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+        }
+    }
+}
+
+impl FromStr for LayerFormat {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid layers format: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// What a layer represents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerKind {
+    Depth,
+    Auxiliary,
+}
+
+impl LayerKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Depth => "depth",
+            Self::Auxiliary => "aux",
+        }
+    }
+}
+
+/// A decoded grayscale layer
+pub struct Layer {
+    pub kind: LayerKind,
+    /// Auxiliary type URN (e.g. `urn:com:apple:photo:2020:aux:hdrgainmap`)
+    pub aux_type: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    /// Significant bits per sample
+    pub bit_depth: u8,
+    /// Tightly packed samples: one byte each, or two native-endian bytes
+    /// each when `bit_depth > 8`
+    pub pixels: Vec<u8>,
+}
+
+impl Layer {
+    /// Samples reduced to 8 bits
+    pub fn samples_8bit(&self) -> Cow<'_, [u8]> {
+        if self.bit_depth <= 8 {
+            return Cow::Borrowed(&self.pixels);
+        }
+        let shift = self.bit_depth - 8;
+        Cow::Owned(
+            self.pixels
+                .chunks_exact(2)
+                .map(|s| (u16::from_ne_bytes([s[0], s[1]]) >> shift).min(255) as u8)
+                .collect(),
+        )
+    }
+
+    /// Samples of a `bit_depth > 8` layer scaled up to the full 16-bit range
+    pub fn samples_16bit(&self) -> Cow<'_, [u8]> {
+        let shift = 16u8.saturating_sub(self.bit_depth);
+        if shift == 0 {
+            return Cow::Borrowed(&self.pixels);
+        }
+        Cow::Owned(
+            self.pixels
+                .chunks_exact(2)
+                .flat_map(|s| (u16::from_ne_bytes([s[0], s[1]]) << shift).to_ne_bytes())
+                .collect(),
+        )
+    }
+}
+
+/// Decode every depth and non-alpha auxiliary image attached to `handle`
+pub fn decode_layers(
+    lib_heif: &LibHeif,
+    handle: &ImageHandle,
+    limits: &Limits,
+) -> Result<Vec<Layer>, ConvertError> {
+    layer_handles(handle)?
+        .into_iter()
+        .map(|(kind, layer, aux_type)| {
+            check_layer(&layer, limits)?;
+            decode_gray(lib_heif, &layer, kind, aux_type)
+        })
+        .collect()
+}
+
+/// Check the layers [`decode_layers`] would decode against `limits`
+///
+/// Returns the memory they hold next to the image while it is converted,
+/// and the most their encoded files can add to a ZIP archive.
+pub fn estimate_memory(
+    handle: &ImageHandle,
+    limits: &Limits,
+) -> Result<(usize, usize), ConvertError> {
+    layer_handles(handle)?
+        .iter()
+        .try_fold((0, 0), |(memory, encoded), (_, layer, _)| {
+            let samples = layer.width() as usize * layer.height() as usize;
+            let bytes_per_sample = if layer.luma_bits_per_pixel() > 8 {
+                2
+            } else {
+                1
+            };
+            Ok((
+                memory + check_layer(layer, limits)?,
+                encoded + samples * bytes_per_sample,
+            ))
+        })
+}
+
+/// Layers are single-channel, so checking them as RGB overestimates their
+/// memory threefold; they are small next to the image itself
+fn check_layer(layer: &ImageHandle, limits: &Limits) -> Result<usize, ConvertError> {
+    limits.check(
+        layer.width(),
+        layer.height(),
+        layer.luma_bits_per_pixel(),
+        false,
+    )
+}
+
+fn layer_handles(
+    handle: &ImageHandle,
+) -> Result<Vec<(LayerKind, ImageHandle, Option<String>)>, ConvertError> {
+    let mut layers = Vec::new();
+
+    let mut depth_ids: Vec<ItemId> = vec![0; handle.number_of_depth_images().max(0) as usize];
+    let count = handle.depth_image_ids(&mut depth_ids);
+    for &id in &depth_ids[..count] {
+        let depth = handle
+            .depth_image_handle(id)
+            .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+        layers.push((LayerKind::Depth, depth, None));
+    }
+
+    // Alpha is handled with the colour image and depth was covered above
+    let filter = AuxiliaryImagesFilter::new().omit_alpha().omit_depth();
+    for aux in handle.auxiliary_images(filter) {
+        let aux_type = aux.auxiliary_type().ok();
+        layers.push((LayerKind::Auxiliary, aux, aux_type));
+    }
+
+    Ok(layers)
+}
+
+fn decode_gray(
+    lib_heif: &LibHeif,
+    handle: &ImageHandle,
+    kind: LayerKind,
+    aux_type: Option<String>,
+) -> Result<Layer, ConvertError> {
+    let image = lib_heif
+        .decode(handle, ColorSpace::Monochrome, None)
+        .map_err(limits::heif_error)?;
+
+    let planes = image.planes();
+    let plane = planes.y.ok_or_else(|| {
+        ConvertError::DecodeError("Failed to get grayscale layer data".to_string())
+    })?;
+
+    let bytes_per_sample = if plane.storage_bits_per_pixel > 8 {
+        2
+    } else {
+        1
+    };
+    let row_len = plane.width as usize * bytes_per_sample;
+    let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    Ok(Layer {
+        kind,
+        aux_type,
+        width: plane.width,
+        height: plane.height,
+        bit_depth: plane.bits_per_pixel,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_8bit_scales_high_bit_depth() {
+        let pixels: Vec<u8> = [0u16, 512, 1023]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        let layer = Layer {
+            kind: LayerKind::Depth,
+            aux_type: None,
+            width: 3,
+            height: 1,
+            bit_depth: 10,
+            pixels,
+        };
+        assert_eq!(layer.samples_8bit().as_ref(), &[0, 128, 255]);
+    }
+
+    #[test]
+    fn test_samples_16bit_scales_high_bit_depth() {
+        let pixels: Vec<u8> = [0u16, 512, 1023]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        let layer = Layer {
+            kind: LayerKind::Depth,
+            aux_type: None,
+            width: 3,
+            height: 1,
+            bit_depth: 10,
+            pixels,
+        };
+        let expected: Vec<u8> = [0u16, 32768, 65472]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        assert_eq!(layer.samples_16bit().as_ref(), expected.as_slice());
+    }
+}
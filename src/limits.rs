@@ -0,0 +1,163 @@
+//! Decode limits and memory estimates
+//!
+//! A per-side limit alone admits a 16384×16384 image (268 MP, about 800 MB
+//! as RGB). Every image, and every resize output, is also checked against
+//! a megapixel limit and an estimate of the memory its conversion needs,
+//! using only the dimensions and bit depth from the headers, before any
+//! pixels are decoded. libheif
+//! gets matching security limits so hostile files are stopped while parsing.
+
+use crate::config::Config;
+use crate::error::ConvertError;
+use libheif_rs::{HeifContext, HeifError, HeifErrorSubCode};
+
+/// Size and memory limits applied to every decoded image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Longest allowed side in pixels
+    pub max_resolution: u32,
+    /// Largest allowed image area in megapixels
+    pub max_megapixels: u32,
+    /// Most memory one job may need, in bytes
+    pub max_job_memory: usize,
+    /// Most children libheif accepts in a single box
+    pub max_children: u32,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_resolution: config.max_resolution,
+            max_megapixels: config.max_megapixels,
+            max_job_memory: config.max_job_memory,
+            max_children: config.max_children,
+        }
+    }
+
+    /// Check an image before decoding it, returning its memory estimate
+    pub fn check(
+        &self,
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+        has_alpha: bool,
+    ) -> Result<usize, ConvertError> {
+        if width > self.max_resolution || height > self.max_resolution {
+            return Err(ConvertError::ImageTooLarge {
+                width,
+                height,
+                max: self.max_resolution,
+            });
+        }
+        if width as u64 * height as u64 > self.max_pixels() {
+            return Err(ConvertError::TooManyPixels {
+                width,
+                height,
+                max_megapixels: self.max_megapixels,
+            });
+        }
+
+        let required = estimate_memory(width, height, bit_depth, has_alpha);
+        if required > self.max_job_memory {
+            return Err(ConvertError::MemoryLimitExceeded {
+                required,
+                max: self.max_job_memory,
+            });
+        }
+        Ok(required)
+    }
+
+    fn max_pixels(&self) -> u64 {
+        self.max_megapixels as u64 * 1_000_000
+    }
+}
+
+/// Peak memory in bytes to convert an image, from its header alone
+///
+/// Counts the decoder's YCbCr planes, the decoded RGB(A) at source bit
+/// depth, an 8-bit working copy for pixel operations and the encoder's
+/// output buffer.
+pub fn estimate_memory(width: u32, height: u32, bit_depth: u8, has_alpha: bool) -> usize {
+    let pixels = width as usize * height as usize;
+    let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+    let channels = if has_alpha { 4 } else { 3 };
+    pixels * channels * (2 * bytes_per_sample + 2)
+}
+
+/// Parse a HEIF file with libheif's security limits set from `limits`
+///
+/// Per-context limits need libheif 1.19 and the total memory limit 1.20;
+/// CI and the Docker image build 1.20.2.
+pub fn read_context<'a>(data: &'a [u8], limits: &Limits) -> Result<HeifContext<'a>, ConvertError> {
+    let mut ctx = HeifContext::new().map_err(heif_error)?;
+    let mut security = ctx.security_limits();
+    security.set_max_image_size_pixels(limits.max_pixels());
+    security.set_max_children_per_box(limits.max_children);
+    security.set_max_memory_block_size(limits.max_job_memory as u64);
+    security.set_max_total_memory(limits.max_job_memory as u64);
+    ctx.set_security_limits(&security).map_err(heif_error)?;
+    ctx.read_bytes(data).map_err(heif_error)?;
+    Ok(ctx)
+}
+
+/// Map a libheif error, keeping security limit violations distinct
+pub fn heif_error(e: HeifError) -> ConvertError {
+    if matches!(e.sub_code, HeifErrorSubCode::SecurityLimitExceeded) {
+        ConvertError::SecurityLimitExceeded(e.message)
+    } else {
+        ConvertError::DecodeError(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_resolution: 16384,
+            max_megapixels: 100,
+            max_job_memory: 2 << 30,
+            max_children: 100,
+        }
+    }
+
+    #[test]
+    fn test_estimate_memory() {
+        // 12 MP 8-bit RGB: 12 bytes per pixel
+        assert_eq!(estimate_memory(4000, 3000, 8, false), 144_000_000);
+        // 10-bit with alpha: 4 channels of 2 + 2 + 1 + 1 bytes
+        assert_eq!(estimate_memory(1000, 1000, 10, true), 24_000_000);
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let limits = limits();
+        assert_eq!(limits.check(4000, 3000, 8, false).unwrap(), 144_000_000);
+
+        // Within the side limit but far above the megapixel limit
+        assert!(matches!(
+            limits.check(16384, 16384, 8, false),
+            Err(ConvertError::TooManyPixels {
+                max_megapixels: 100,
+                ..
+            })
+        ));
+        assert!(matches!(
+            limits.check(20000, 100, 8, false),
+            Err(ConvertError::ImageTooLarge { .. })
+        ));
+
+        let tight = Limits {
+            max_job_memory: 100_000_000,
+            ..limits
+        };
+        assert!(matches!(
+            tight.check(4000, 3000, 8, false),
+            Err(ConvertError::MemoryLimitExceeded {
+                required: 144_000_000,
+                max: 100_000_000
+            })
+        ));
+    }
+}
@@ -2,13 +2,27 @@
 //!
 //! A production-grade, super-fast HEIC to JPG converter built in Rust.
 
+mod archive;
+mod avif;
+mod buffers;
+mod color;
 mod config;
 mod converter;
+mod crop;
 mod error;
 mod handlers;
+mod isobmff;
+mod layers;
+mod limits;
+mod metadata;
+mod probe;
+mod raster;
+mod resize;
 mod router;
 mod state;
+mod tonemap;
 mod worker;
+mod ycbcr;
 
 use crate::config::Config;
 use crate::router::create_router;
@@ -44,9 +58,16 @@ async fn main() {
     // }
     // info!(dir = config.upload_dir, "Upload directory verified");
 
+    // Share released buffers between jobs
+    buffers::init(&config);
+
     // Create worker pool
     let worker_pool = WorkerPool::new(&config);
-    info!(workers = config.worker_count, "Worker pool initialized");
+    info!(
+        workers = config.worker_count,
+        memory_budget_mb = config.memory_budget / 1024 / 1024,
+        "Worker pool initialized"
+    );
 
     // Create shared app state
     let app_state = Arc::new(AppState {
@@ -0,0 +1,575 @@
+//! Metadata transfer from HEIF containers into JPEG, PNG and WebP output
+//!
+//! HEIF stores Exif as a separate item next to the image, while JPEG expects
+//! it inline as an APP1 marker segment right after the start-of-image marker.
+//! ICC profiles go into one or more APP2 segments. PNG carries Exif in an
+//! `eXIf` chunk; WebP needs the extended container with `ICCP`/`EXIF` chunks.
+
+use libheif_rs::{ImageHandle, ItemId};
+use tracing::warn;
+
+/// Identifier that prefixes the Exif payload of a JPEG APP1 segment
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Identifier that prefixes every ICC chunk in a JPEG APP2 segment
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// Largest payload a JPEG marker segment can hold (the length field counts itself)
+const MAX_SEGMENT_PAYLOAD: usize = u16::MAX as usize - 2;
+
+/// Signature that starts every PNG stream
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Size of the IHDR chunk: length, type, 13 data bytes and CRC
+const PNG_IHDR_LEN: usize = 25;
+
+/// VP8X feature flags
+const WEBP_FLAG_ICC: u8 = 0x20;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_EXIF: u8 = 0x08;
+
+/// Read the Exif block attached to an image handle
+///
+/// Returns the bare TIFF structure (starting with `II*\0` or `MM\0*`), or
+/// `None` if the image carries no usable Exif data.
+pub fn read_exif(handle: &ImageHandle) -> Option<Vec<u8>> {
+    let mut meta_ids: Vec<ItemId> = vec![0; 1];
+    let count = handle.metadata_block_ids(&mut meta_ids, b"Exif");
+    if count == 0 {
+        return None;
+    }
+
+    let raw = handle.metadata(meta_ids[0]).ok()?;
+    let tiff = tiff_from_heif_exif(&raw);
+    if tiff.is_none() {
+        warn!(size = raw.len(), "Ignoring malformed Exif block");
+    }
+    tiff.map(<[u8]>::to_vec)
+}
+
+/// Locate the TIFF header inside a HEIF Exif item
+///
+/// The item starts with a 4-byte big-endian offset to the TIFF header. Some
+/// writers get the offset wrong, so fall back to looking for the JPEG-style
+/// `Exif\0\0` prefix.
+fn tiff_from_heif_exif(raw: &[u8]) -> Option<&[u8]> {
+    let prefix: [u8; 4] = raw.get(..4)?.try_into().ok()?;
+    let offset = u32::from_be_bytes(prefix) as usize;
+
+    if let Some(tiff) = offset.checked_add(4).and_then(|start| raw.get(start..)) {
+        if is_tiff_header(tiff) {
+            return Some(tiff);
+        }
+    }
+
+    let start = raw
+        .windows(EXIF_HEADER.len())
+        .position(|w| w == EXIF_HEADER)?
+        + EXIF_HEADER.len();
+    raw.get(start..).filter(|tiff| is_tiff_header(tiff))
+}
+
+/// Locate the TIFF header in an Exif block from a PNG or WebP file
+///
+/// The block should be bare TIFF, but some writers keep the JPEG-style
+/// `Exif\0\0` prefix.
+pub fn tiff_from_exif(raw: &[u8]) -> Option<&[u8]> {
+    let tiff = raw.strip_prefix(EXIF_HEADER).unwrap_or(raw);
+    is_tiff_header(tiff).then_some(tiff)
+}
+
+/// Exif block and ICC profile carried by a JPEG stream
+#[derive(Debug, Default, PartialEq)]
+pub struct JpegMetadata {
+    /// Bare TIFF structure from the APP1 Exif segment
+    pub exif: Option<Vec<u8>>,
+    /// ICC profile reassembled from its APP2 chunks
+    pub icc: Option<Vec<u8>>,
+}
+
+/// Read Exif and ICC data from the segments ahead of the image data
+pub fn read_jpeg_metadata(jpeg: &[u8]) -> JpegMetadata {
+    let mut metadata = JpegMetadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+
+    let mut pos = 2;
+    while jpeg.starts_with(&[0xFF, 0xD8]) && pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        // Start of scan: metadata segments all come before it
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let Some(payload) = jpeg.get(pos + 4..pos + 2 + len) else {
+            break;
+        };
+        match marker {
+            0xE1 if metadata.exif.is_none() => {
+                metadata.exif = payload
+                    .strip_prefix(EXIF_HEADER)
+                    .filter(|tiff| is_tiff_header(tiff))
+                    .map(<[u8]>::to_vec);
+            }
+            // Chunk sequence number (1-based) and count precede the data
+            0xE2 => {
+                if let Some(chunk) = payload.strip_prefix(ICC_HEADER) {
+                    if chunk.len() > 2 {
+                        icc_chunks.push((chunk[0], &chunk[2..]));
+                    }
+                }
+            }
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        metadata.icc = Some(
+            icc_chunks
+                .iter()
+                .flat_map(|(_, data)| *data)
+                .copied()
+                .collect(),
+        );
+    }
+    metadata
+}
+
+fn is_tiff_header(data: &[u8]) -> bool {
+    data.starts_with(b"II*\0") || data.starts_with(b"MM\0*")
+}
+
+/// Exif tag holding the image orientation
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// TIFF field type SHORT (u16)
+const TYPE_SHORT: u16 = 3;
+
+/// Byte order of a TIFF structure
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn of(tiff: &[u8]) -> Option<Self> {
+        match tiff.get(..2)? {
+            b"II" => Some(Self::Little),
+            b"MM" => Some(Self::Big),
+            _ => None,
+        }
+    }
+
+    fn u16(self, data: &[u8], pos: usize) -> Option<u16> {
+        let bytes = data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(match self {
+            Self::Little => u16::from_le_bytes(bytes),
+            Self::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(self, data: &[u8], pos: usize) -> Option<u32> {
+        let bytes = data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn u16_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Build a 12-byte IFD entry holding a single SHORT value
+fn short_entry(order: ByteOrder, tag: u16, value: u16) -> [u8; 12] {
+    let mut entry = [0u8; 12];
+    entry[0..2].copy_from_slice(&order.u16_bytes(tag));
+    entry[2..4].copy_from_slice(&order.u16_bytes(TYPE_SHORT));
+    entry[4..8].copy_from_slice(&order.u32_bytes(1));
+    entry[8..10].copy_from_slice(&order.u16_bytes(value));
+    entry
+}
+
+/// Read the Orientation tag from IFD0 of a TIFF structure
+pub fn read_orientation(tiff: &[u8]) -> Option<u16> {
+    let order = ByteOrder::of(tiff)?;
+    let ifd = order.u32(tiff, 4)? as usize;
+    let count = order.u16(tiff, ifd)? as usize;
+    (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&pos| order.u16(tiff, pos) == Some(ORIENTATION_TAG))
+        .and_then(|pos| order.u16(tiff, pos + 8))
+        .filter(|value| (1..=8).contains(value))
+}
+
+/// Set the Orientation tag in IFD0 of a TIFF structure
+///
+/// An existing tag is overwritten in place. If the tag is missing, a copy of
+/// IFD0 with the tag added is appended and the header pointed at it; all
+/// other offsets stay valid because nothing before the copy moves.
+/// Returns `false` if the structure could not be parsed.
+pub fn set_orientation(tiff: &mut Vec<u8>, orientation: u16) -> bool {
+    let Some(order) = ByteOrder::of(tiff) else {
+        return false;
+    };
+    let Some(ifd) = order.u32(tiff, 4).map(|v| v as usize) else {
+        return false;
+    };
+    let Some(count) = order.u16(tiff, ifd).map(|v| v as usize) else {
+        return false;
+    };
+    let entries_start = ifd + 2;
+    let entries_end = entries_start + count * 12;
+    let Some(next_ifd) = order.u32(tiff, entries_end) else {
+        return false;
+    };
+
+    for pos in (entries_start..entries_end).step_by(12) {
+        if order.u16(tiff, pos) == Some(ORIENTATION_TAG) {
+            tiff[pos..pos + 12].copy_from_slice(&short_entry(order, ORIENTATION_TAG, orientation));
+            return true;
+        }
+    }
+
+    let mut entries: Vec<[u8; 12]> = tiff[entries_start..entries_end]
+        .chunks_exact(12)
+        .filter_map(|chunk| chunk.try_into().ok())
+        .collect();
+    entries.push(short_entry(order, ORIENTATION_TAG, orientation));
+    entries.sort_by_key(|entry| order.u16(entry, 0).unwrap_or(0));
+
+    // IFDs must start on a word boundary
+    if tiff.len() % 2 == 1 {
+        tiff.push(0);
+    }
+    let new_ifd = tiff.len() as u32;
+    tiff.extend_from_slice(&order.u16_bytes(entries.len() as u16));
+    for entry in &entries {
+        tiff.extend_from_slice(entry);
+    }
+    tiff.extend_from_slice(&order.u32_bytes(next_ifd));
+    tiff[4..8].copy_from_slice(&order.u32_bytes(new_ifd));
+    true
+}
+
+/// Minimal TIFF structure carrying only an Orientation tag
+pub fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let order = ByteOrder::Big;
+    let mut tiff = b"MM\0*".to_vec();
+    tiff.extend_from_slice(&order.u32_bytes(8));
+    tiff.extend_from_slice(&order.u16_bytes(1));
+    tiff.extend_from_slice(&short_entry(order, ORIENTATION_TAG, orientation));
+    tiff.extend_from_slice(&order.u32_bytes(0));
+    tiff
+}
+
+/// Build an APP1 segment carrying the given TIFF structure
+///
+/// Returns `None` if the block does not fit into a single marker segment.
+pub fn exif_segment(tiff: &[u8]) -> Option<Vec<u8>> {
+    let payload_len = EXIF_HEADER.len() + tiff.len();
+    if payload_len > MAX_SEGMENT_PAYLOAD {
+        warn!(
+            size = tiff.len(),
+            "Exif block too large for APP1, dropping it"
+        );
+        return None;
+    }
+
+    let mut segment = Vec::with_capacity(payload_len + 4);
+    segment.extend_from_slice(&[0xFF, 0xE1]);
+    segment.extend_from_slice(&((payload_len + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(tiff);
+    Some(segment)
+}
+
+/// Build the APP2 segments carrying an ICC profile
+///
+/// Profiles larger than one segment are split into numbered chunks, as
+/// described in the ICC specification (Annex B.4).
+pub fn icc_segments(icc: &[u8]) -> Vec<Vec<u8>> {
+    // Header plus sequence number and chunk count bytes
+    let max_chunk = MAX_SEGMENT_PAYLOAD - ICC_HEADER.len() - 2;
+    let count = icc.len().div_ceil(max_chunk);
+    if count == 0 || count > u8::MAX as usize {
+        return Vec::new();
+    }
+
+    icc.chunks(max_chunk)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let payload_len = ICC_HEADER.len() + 2 + chunk.len();
+            let mut segment = Vec::with_capacity(payload_len + 4);
+            segment.extend_from_slice(&[0xFF, 0xE2]);
+            segment.extend_from_slice(&((payload_len + 2) as u16).to_be_bytes());
+            segment.extend_from_slice(ICC_HEADER);
+            segment.push(i as u8 + 1);
+            segment.push(count as u8);
+            segment.extend_from_slice(chunk);
+            segment
+        })
+        .collect()
+}
+
+/// Splice marker segments into a JPEG stream
+///
+/// Segments go after SOI and the JFIF APP0 segment (if present), which is
+/// where readers expect Exif and ICC data. The result is always a fresh,
+/// exactly sized copy so `jpeg` can be a reused scratch buffer.
+pub fn insert_segments(jpeg: &[u8], segments: &[Vec<u8>]) -> Vec<u8> {
+    if segments.is_empty() || !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return jpeg.to_vec();
+    }
+
+    let mut pos = 2;
+    if jpeg.len() >= pos + 4 && jpeg[pos] == 0xFF && jpeg[pos + 1] == 0xE0 {
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        pos = (pos + 2 + len).min(jpeg.len());
+    }
+
+    let extra: usize = segments.iter().map(Vec::len).sum();
+    let mut out = Vec::with_capacity(jpeg.len() + extra);
+    out.extend_from_slice(&jpeg[..pos]);
+    for segment in segments {
+        out.extend_from_slice(segment);
+    }
+    out.extend_from_slice(&jpeg[pos..]);
+    out
+}
+
+/// Insert an `eXIf` chunk carrying the given TIFF structure into a PNG stream
+///
+/// The chunk goes right after IHDR, which keeps it ahead of the image data
+/// as the PNG specification requires.
+pub fn insert_png_exif(png: Vec<u8>, tiff: &[u8]) -> Vec<u8> {
+    let pos = PNG_SIGNATURE.len() + PNG_IHDR_LEN;
+    if !png.starts_with(PNG_SIGNATURE) || png.len() < pos || &png[12..16] != b"IHDR" {
+        return png;
+    }
+
+    let mut out = Vec::with_capacity(png.len() + tiff.len() + 12);
+    out.extend_from_slice(&png[..pos]);
+    out.extend_from_slice(&(tiff.len() as u32).to_be_bytes());
+    let chunk_start = out.len();
+    out.extend_from_slice(b"eXIf");
+    out.extend_from_slice(tiff);
+    let crc = crc32fast::hash(&out[chunk_start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out.extend_from_slice(&png[pos..]);
+    out
+}
+
+/// Add ICC and Exif chunks to a WebP stream
+///
+/// Simple (`VP8 `/`VP8L` only) files are rewritten into the extended format
+/// with a `VP8X` header; an existing `VP8X` header keeps its flags.
+pub fn insert_webp_metadata(
+    webp: Vec<u8>,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+) -> Vec<u8> {
+    if (icc.is_none() && exif.is_none())
+        || webp.len() < 12
+        || &webp[..4] != b"RIFF"
+        || &webp[8..12] != b"WEBP"
+    {
+        return webp;
+    }
+
+    let mut flags = if has_alpha { WEBP_FLAG_ALPHA } else { 0 };
+    let mut body = &webp[12..];
+    if body.len() >= 18 && &body[..4] == b"VP8X" {
+        flags |= body[8];
+        body = &body[18..];
+    }
+    if icc.is_some() {
+        flags |= WEBP_FLAG_ICC;
+    }
+    if exif.is_some() {
+        flags |= WEBP_FLAG_EXIF;
+    }
+
+    let mut vp8x = [0u8; 10];
+    vp8x[0] = flags;
+    vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let extra = 18 + icc.map_or(0, |d| d.len() + 9) + exif.map_or(0, |d| d.len() + 9);
+    let mut out = Vec::with_capacity(webp.len() + extra);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    push_riff_chunk(&mut out, b"VP8X", &vp8x);
+    if let Some(icc) = icc {
+        push_riff_chunk(&mut out, b"ICCP", icc);
+    }
+    out.extend_from_slice(body);
+    if let Some(exif) = exif {
+        push_riff_chunk(&mut out, b"EXIF", exif);
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    out
+}
+
+/// Append a RIFF chunk, padded to an even length
+fn push_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiff_from_heif_exif() {
+        let mut raw = vec![0, 0, 0, 6];
+        raw.extend_from_slice(b"Exif\0\0MM\0*rest");
+        assert_eq!(tiff_from_heif_exif(&raw), Some(&b"MM\0*rest"[..]));
+
+        // Wrong offset, recovered through the Exif header
+        raw[3] = 40;
+        assert_eq!(tiff_from_heif_exif(&raw), Some(&b"MM\0*rest"[..]));
+
+        assert_eq!(tiff_from_heif_exif(&[0, 0, 0, 0, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_insert_segments_after_app0() {
+        let jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0xAA, 0xBB, 0xFF, 0xDB];
+        let segment = exif_segment(b"II*\0").unwrap();
+        let out = insert_segments(&jpeg, std::slice::from_ref(&segment));
+
+        assert_eq!(&out[..8], &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0xAA, 0xBB]);
+        assert_eq!(&out[8..8 + segment.len()], segment.as_slice());
+        assert_eq!(&out[8 + segment.len()..], &[0xFF, 0xDB]);
+    }
+
+    #[test]
+    fn test_insert_png_exif_after_ihdr() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&13u32.to_be_bytes());
+        png.extend_from_slice(b"IHDR");
+        png.extend_from_slice(&[0; 17]);
+        png.extend_from_slice(b"\0\0\0\0IEND\xAE\x42\x60\x82");
+
+        let out = insert_png_exif(png.clone(), b"MM\0*");
+        assert_eq!(&out[..33], &png[..33]);
+        assert_eq!(&out[33..45], b"\0\0\0\x04eXIfMM\0*");
+        assert_eq!(&out[45..49], &crc32fast::hash(b"eXIfMM\0*").to_be_bytes());
+        assert_eq!(&out[49..], &png[33..]);
+
+        // Not a PNG: left alone
+        assert_eq!(insert_png_exif(vec![1, 2, 3], b"MM\0*"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_insert_webp_metadata_adds_vp8x() {
+        let mut webp = b"RIFF\x0e\0\0\0WEBPVP8L\x02\0\0\0\xAA\xBB".to_vec();
+        let out = insert_webp_metadata(webp.clone(), 640, 480, true, Some(b"icc"), Some(b"II*\0"));
+
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize,
+            out.len() - 8
+        );
+        assert_eq!(&out[12..20], b"VP8X\x0a\0\0\0");
+        assert_eq!(out[20], WEBP_FLAG_ICC | WEBP_FLAG_ALPHA | WEBP_FLAG_EXIF);
+        assert_eq!(&out[24..30], &[0x7F, 0x02, 0x00, 0xDF, 0x01, 0x00]);
+        // ICC padded to an even length, then the image data, then Exif
+        assert_eq!(&out[30..42], b"ICCP\x03\0\0\0icc\0");
+        assert_eq!(&out[42..52], &webp[12..]);
+        assert_eq!(&out[52..], b"EXIF\x04\0\0\0II*\0");
+
+        // Nothing to add: left alone
+        webp.truncate(12);
+        assert_eq!(
+            insert_webp_metadata(webp.clone(), 1, 1, false, None, None),
+            webp
+        );
+    }
+
+    #[test]
+    fn test_set_orientation() {
+        let mut tiff = orientation_exif(6);
+        assert_eq!(read_orientation(&tiff), Some(6));
+        assert!(set_orientation(&mut tiff, 1));
+        assert_eq!(read_orientation(&tiff), Some(1));
+
+        // Little-endian IFD0 with only an ImageWidth tag (0x0100)
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&short_entry(ByteOrder::Little, 0x0100, 640));
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+
+        assert!(set_orientation(&mut tiff, 8));
+        assert_eq!(read_orientation(&tiff), Some(8));
+        let ifd = ByteOrder::Little.u32(&tiff, 4).unwrap() as usize;
+        assert_eq!(ByteOrder::Little.u16(&tiff, ifd), Some(2));
+        assert_eq!(ByteOrder::Little.u16(&tiff, ifd + 2), Some(0x0100));
+
+        assert!(!set_orientation(&mut b"junk".to_vec(), 1));
+    }
+
+    #[test]
+    fn test_read_jpeg_metadata() {
+        let tiff = orientation_exif(6);
+        let icc: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let mut segments = vec![exif_segment(&tiff).unwrap()];
+        // Chunks out of order are put back in sequence
+        segments.extend(icc_segments(&icc).into_iter().rev());
+        let jpeg = insert_segments(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34], &segments);
+
+        let metadata = read_jpeg_metadata(&jpeg);
+        assert_eq!(metadata.exif.as_deref(), Some(tiff.as_slice()));
+        assert_eq!(read_orientation(metadata.exif.as_deref().unwrap()), Some(6));
+        assert_eq!(metadata.icc, Some(icc));
+
+        assert_eq!(read_jpeg_metadata(b"not a jpeg"), JpegMetadata::default());
+        assert_eq!(
+            tiff_from_exif(&[EXIF_HEADER, &tiff].concat()),
+            Some(&tiff[..])
+        );
+        assert_eq!(tiff_from_exif(b"junk"), None);
+    }
+
+    #[test]
+    fn test_icc_segments_chunking() {
+        let icc = vec![7u8; 100_000];
+        let segments = icc_segments(&icc);
+        assert_eq!(segments.len(), 2);
+
+        for (i, segment) in segments.iter().enumerate() {
+            let len = u16::from_be_bytes([segment[2], segment[3]]) as usize;
+            assert_eq!(len + 2, segment.len());
+            assert_eq!(&segment[4..16], ICC_HEADER);
+            assert_eq!(segment[16], i as u8 + 1);
+            assert_eq!(segment[17], 2);
+        }
+
+        let total: usize = segments.iter().map(|s| s.len() - 18).sum();
+        assert_eq!(total, icc.len());
+    }
+}
@@ -0,0 +1,145 @@
+//! Container inspection without decoding
+//!
+//! Reads the HEIF structure through `HeifContext` to report what a file
+//! contains. Only headers and item properties are parsed; the HEVC/AV1
+//! decoder never runs, so probing a large file costs a fraction of converting it.
+
+use crate::converter::{sniff_input, InputFormat};
+use crate::error::ConvertError;
+use crate::isobmff;
+use crate::limits::{self, Limits};
+use libheif_rs::{AuxiliaryImagesFilter, ImageHandle, ItemId};
+use serde::Serialize;
+
+/// Everything `/api/probe` reports about a file
+#[derive(Debug, Serialize)]
+pub struct ProbeInfo {
+    /// `heic`, `heif` or `avif`
+    pub format: &'static str,
+    /// Major brand from the `ftyp` box, e.g. `heic`
+    pub brand: String,
+    pub compatible_brands: Vec<String>,
+    /// Number of top-level images
+    pub image_count: usize,
+    /// Top-level images in file order
+    pub images: Vec<ProbeImage>,
+}
+
+/// Properties of one top-level image
+#[derive(Debug, Serialize)]
+pub struct ProbeImage {
+    pub index: usize,
+    pub primary: bool,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per luma sample
+    pub bit_depth: u8,
+    /// Bits per chroma sample
+    pub chroma_bit_depth: u8,
+    pub has_alpha: bool,
+    /// Embedded thumbnails as `[width, height]`
+    pub thumbnails: Vec<[u32; 2]>,
+    pub has_depth: bool,
+    /// Auxiliary images other than alpha and depth
+    pub auxiliary_count: usize,
+    /// Types of the attached metadata blocks, e.g. `Exif` or `mime`
+    pub metadata: Vec<String>,
+    /// `icc`, `nclx`, or `None` when the image carries no colour profile
+    pub color_profile: Option<&'static str>,
+    /// Estimated peak memory to convert this image, in bytes
+    pub memory: usize,
+}
+
+/// Inspect a HEIF file, rejecting images over `limits` exactly as a
+/// conversion would
+pub fn probe(data: &[u8], limits: &Limits) -> Result<ProbeInfo, ConvertError> {
+    let format = match sniff_input(data)? {
+        InputFormat::Heif(format) => format,
+        InputFormat::Raster(format) => {
+            return Err(ConvertError::ValidationError(format!(
+                "Probe only inspects HEIF-family files, not {}",
+                format.as_str().to_uppercase()
+            )))
+        }
+    };
+    let ctx = limits::read_context(data, limits)?;
+
+    let images = ctx
+        .image_ids()
+        .into_iter()
+        .enumerate()
+        .map(|(index, id)| {
+            let handle = ctx
+                .image_handle(id)
+                .map_err(|e| ConvertError::DecodeError(e.to_string()))?;
+            let memory = limits.check(
+                handle.width(),
+                handle.height(),
+                handle.luma_bits_per_pixel(),
+                handle.has_alpha_channel(),
+            )?;
+            Ok(probe_image(index, &handle, memory))
+        })
+        .collect::<Result<Vec<_>, ConvertError>>()?;
+
+    let file_type = isobmff::file_type(data);
+    Ok(ProbeInfo {
+        format: format.as_str(),
+        brand: file_type
+            .as_ref()
+            .map(|ftyp| isobmff::fourcc(&ftyp.major_brand))
+            .unwrap_or_default(),
+        compatible_brands: file_type
+            .iter()
+            .flat_map(|ftyp| &ftyp.compatible_brands)
+            .map(isobmff::fourcc)
+            .collect(),
+        image_count: images.len(),
+        images,
+    })
+}
+
+fn probe_image(index: usize, handle: &ImageHandle, memory: usize) -> ProbeImage {
+    let mut thumbnail_ids: Vec<ItemId> = vec![0; handle.number_of_thumbnails()];
+    let count = handle.thumbnail_ids(&mut thumbnail_ids);
+    let thumbnails = thumbnail_ids[..count]
+        .iter()
+        .filter_map(|&id| handle.thumbnail(id).ok())
+        .map(|thumb| [thumb.width(), thumb.height()])
+        .collect();
+
+    // An all-zero filter selects every metadata type
+    let mut metadata_ids: Vec<ItemId> =
+        vec![0; handle.number_of_metadata_blocks(&[0; 4]).max(0) as usize];
+    let count = handle.metadata_block_ids(&mut metadata_ids, &[0; 4]);
+    let metadata = metadata_ids[..count]
+        .iter()
+        .filter_map(|&id| handle.metadata_type(id))
+        .map(str::to_string)
+        .collect();
+
+    let color_profile = if handle.color_profile_raw().is_some() {
+        Some("icc")
+    } else if handle.color_profile_nclx().is_some() {
+        Some("nclx")
+    } else {
+        None
+    };
+
+    let filter = AuxiliaryImagesFilter::new().omit_alpha().omit_depth();
+    ProbeImage {
+        index,
+        primary: handle.is_primary(),
+        width: handle.width(),
+        height: handle.height(),
+        bit_depth: handle.luma_bits_per_pixel(),
+        chroma_bit_depth: handle.chroma_bits_per_pixel(),
+        has_alpha: handle.has_alpha_channel(),
+        thumbnails,
+        has_depth: handle.number_of_depth_images() > 0,
+        auxiliary_count: handle.auxiliary_images(filter).len(),
+        metadata,
+        color_profile,
+        memory,
+    }
+}
@@ -0,0 +1,287 @@
+//! JPEG, PNG and WebP inputs
+//!
+//! Camera rolls mix HEIC with plain rasters. These are decoded to the same
+//! interleaved RGB(A) the HEIF path produces, together with their ICC
+//! profile, Exif block and Exif orientation, so every later step treats
+//! both alike. JPEG goes through turbojpeg, PNG and WebP through `image`.
+
+use crate::buffers;
+use crate::error::ConvertError;
+use crate::limits::Limits;
+use crate::metadata;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{DynamicImage, ImageDecoder};
+use std::cell::RefCell;
+use std::io::Cursor;
+use turbojpeg::{Decompressor, Image, PixelFormat};
+
+// Thread-local Decompressor for JPEG input
+thread_local! {
+    static DECOMPRESSOR: RefCell<Decompressor> = RefCell::new(
+        Decompressor::new().expect("Failed to create Decompressor")
+    );
+}
+
+/// Raster formats recognised by their signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterFormat {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl RasterFormat {
+    /// Detect the format from the leading bytes
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Self::WebP)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+}
+
+/// A decoded raster and the metadata it carried
+pub struct RasterImage {
+    /// Interleaved RGB, or RGBA when `has_alpha` is set, as stored
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub has_alpha: bool,
+    pub icc: Option<Vec<u8>>,
+    /// Bare TIFF structure
+    pub exif: Option<Vec<u8>>,
+    /// Exif Orientation of the stored pixels
+    pub orientation: u16,
+}
+
+/// Decode a raster, checking it against `limits` before any pixels are
+/// allocated
+pub fn decode(
+    data: &[u8],
+    format: RasterFormat,
+    limits: &Limits,
+) -> Result<RasterImage, ConvertError> {
+    let mut image = match format {
+        RasterFormat::Jpeg => decode_jpeg(data, limits)?,
+        RasterFormat::Png => decode_with(
+            PngDecoder::new(Cursor::new(data)).map_err(decode_error)?,
+            limits,
+        )?,
+        RasterFormat::WebP => decode_with(
+            WebPDecoder::new(Cursor::new(data)).map_err(decode_error)?,
+            limits,
+        )?,
+    };
+
+    image.orientation = image
+        .exif
+        .as_deref()
+        .and_then(metadata::read_orientation)
+        .unwrap_or(1);
+    Ok(image)
+}
+
+/// Read a raster's width, height, bit depth and alpha from its header alone
+pub fn read_header(
+    data: &[u8],
+    format: RasterFormat,
+) -> Result<(u32, u32, u8, bool), ConvertError> {
+    match format {
+        RasterFormat::Jpeg => DECOMPRESSOR.with(|decompressor| {
+            let header = decompressor
+                .borrow_mut()
+                .read_header(data)
+                .map_err(decode_error)?;
+            Ok((header.width as u32, header.height as u32, 8, false))
+        }),
+        RasterFormat::Png => Ok(header_info(
+            &PngDecoder::new(Cursor::new(data)).map_err(decode_error)?,
+        )),
+        RasterFormat::WebP => Ok(header_info(
+            &WebPDecoder::new(Cursor::new(data)).map_err(decode_error)?,
+        )),
+    }
+}
+
+fn decode_error(e: impl std::fmt::Display) -> ConvertError {
+    ConvertError::DecodeError(e.to_string())
+}
+
+fn decode_jpeg(data: &[u8], limits: &Limits) -> Result<RasterImage, ConvertError> {
+    DECOMPRESSOR.with(|decompressor| {
+        let mut decompressor = decompressor.borrow_mut();
+        let header = decompressor.read_header(data).map_err(decode_error)?;
+        limits.check(header.width as u32, header.height as u32, 8, false)?;
+
+        let pitch = header.width * 3;
+        let mut pixels = buffers::take_filled(pitch * header.height);
+        decompressor
+            .decompress(
+                data,
+                Image {
+                    pixels: pixels.as_mut_slice(),
+                    width: header.width,
+                    pitch,
+                    height: header.height,
+                    format: PixelFormat::RGB,
+                },
+            )
+            .map_err(decode_error)?;
+
+        let metadata = metadata::read_jpeg_metadata(data);
+        Ok(RasterImage {
+            pixels,
+            width: header.width as u32,
+            height: header.height as u32,
+            has_alpha: false,
+            icc: metadata.icc,
+            exif: metadata.exif,
+            orientation: 1,
+        })
+    })
+}
+
+fn decode_with<D: ImageDecoder>(
+    mut decoder: D,
+    limits: &Limits,
+) -> Result<RasterImage, ConvertError> {
+    check_header(&decoder, limits)?;
+    let (width, height) = decoder.dimensions();
+    let has_alpha = decoder.color_type().has_alpha();
+
+    let icc = decoder.icc_profile().map_err(decode_error)?;
+    let exif = decoder
+        .exif_metadata()
+        .map_err(decode_error)?
+        .and_then(|raw| metadata::tiff_from_exif(&raw).map(<[u8]>::to_vec));
+
+    // 16-bit and grayscale samples are reduced to 8-bit RGB(A)
+    let image = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    let pixels = if has_alpha {
+        image.into_rgba8().into_raw()
+    } else {
+        image.into_rgb8().into_raw()
+    };
+
+    Ok(RasterImage {
+        pixels,
+        width,
+        height,
+        has_alpha,
+        icc,
+        exif,
+        orientation: 1,
+    })
+}
+
+fn check_header<D: ImageDecoder>(decoder: &D, limits: &Limits) -> Result<usize, ConvertError> {
+    let (width, height, bit_depth, has_alpha) = header_info(decoder);
+    limits.check(width, height, bit_depth, has_alpha)
+}
+
+fn header_info<D: ImageDecoder>(decoder: &D) -> (u32, u32, u8, bool) {
+    let (width, height) = decoder.dimensions();
+    let color = decoder.color_type();
+    let bit_depth = color.bytes_per_pixel() / color.channel_count() * 8;
+    (width, height, bit_depth, color.has_alpha())
+}
+
+/// Rotate and mirror interleaved pixels so that they display upright
+///
+/// Returns the new buffer and size; the input goes back to the buffer pool.
+pub fn apply_orientation(
+    pixels: Vec<u8>,
+    channels: usize,
+    (width, height): (u32, u32),
+    orientation: u16,
+) -> (Vec<u8>, u32, u32) {
+    if !(2..=8).contains(&orientation) {
+        return (pixels, width, height);
+    }
+
+    let (w, h) = (width as usize, height as usize);
+    let (dw, dh) = if orientation >= 5 { (h, w) } else { (w, h) };
+    let mut out = buffers::take(pixels.len());
+    for dy in 0..dh {
+        for dx in 0..dw {
+            // Source pixel shown at (dx, dy)
+            let (x, y) = match orientation {
+                2 => (w - 1 - dx, dy),
+                3 => (w - 1 - dx, h - 1 - dy),
+                4 => (dx, h - 1 - dy),
+                5 => (dy, dx),
+                6 => (dy, h - 1 - dx),
+                7 => (w - 1 - dy, h - 1 - dx),
+                _ => (w - 1 - dy, dx),
+            };
+            let start = (y * w + x) * channels;
+            out.extend_from_slice(&pixels[start..start + channels]);
+        }
+    }
+    buffers::give(pixels);
+    (out, dw as u32, dh as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(
+            RasterFormat::sniff(&[0xFF, 0xD8, 0xFF, 0xE1]),
+            Some(RasterFormat::Jpeg)
+        );
+        assert_eq!(
+            RasterFormat::sniff(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"),
+            Some(RasterFormat::Png)
+        );
+        assert_eq!(
+            RasterFormat::sniff(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(RasterFormat::WebP)
+        );
+        assert_eq!(RasterFormat::sniff(b"\0\0\0\x18ftypheic"), None);
+    }
+
+    #[test]
+    fn test_apply_orientation() {
+        // 3x2 gray-as-RGB pixels numbered in reading order
+        let pixels: Vec<u8> = (1..=6).flat_map(|v| [v; 3]).collect();
+        let values = |(out, w, h): (Vec<u8>, u32, u32)| {
+            let v: Vec<u8> = out.chunks(3).map(|px| px[0]).collect();
+            (v, w, h)
+        };
+
+        assert_eq!(
+            values(apply_orientation(pixels.clone(), 3, (3, 2), 1)),
+            (vec![1, 2, 3, 4, 5, 6], 3, 2)
+        );
+        assert_eq!(
+            values(apply_orientation(pixels.clone(), 3, (3, 2), 3)),
+            (vec![6, 5, 4, 3, 2, 1], 3, 2)
+        );
+        // 90° clockwise: the bottom-left pixel ends up top-left
+        assert_eq!(
+            values(apply_orientation(pixels.clone(), 3, (3, 2), 6)),
+            (vec![4, 1, 5, 2, 6, 3], 2, 3)
+        );
+        assert_eq!(
+            values(apply_orientation(pixels, 3, (3, 2), 8)),
+            (vec![3, 6, 2, 5, 1, 4], 2, 3)
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Server-side resizing
+//!
+//! Resampling uses a Lanczos3 filter from the `image` crate. Fit modes follow
+//! the usual CSS/sharp semantics.
+
+use crate::crop::Gravity;
+use crate::error::ConvertError;
+use crate::limits::Limits;
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Pixel, Rgb, Rgba};
+use std::str::FromStr;
+
+/// How the image is fitted into the requested box when both sides are given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitMode {
+    /// Keep aspect ratio, fit inside the box and pad to its exact size
+    Contain,
+    /// Keep aspect ratio, fill the box and crop the overflow
+    Cover,
+    /// Stretch to the exact size, ignoring aspect ratio
+    Fill,
+    /// Keep aspect ratio, fit inside the box without padding
+    Inside,
+}
+
+impl FromStr for FitMode {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "contain" => Ok(Self::Contain),
+            "cover" => Ok(Self::Cover),
+            "fill" => Ok(Self::Fill),
+            "inside" => Ok(Self::Inside),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid fit mode: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Where the resampled image ends up in the output
+#[derive(Debug)]
+struct Layout {
+    /// Size the source is resampled to
+    scaled: (u32, u32),
+    /// Final output size
+    canvas: (u32, u32),
+    /// Top-left of the resampled image on the canvas (negative when cropping)
+    offset: (i64, i64),
+}
+
+/// Work out resampled and final sizes for a resize request
+///
+/// `gravity` picks the part kept by [`FitMode::Cover`] and where
+/// [`FitMode::Contain`] places the image inside its padding.
+fn layout(
+    src: (u32, u32),
+    (width, height): (Option<u32>, Option<u32>),
+    fit: FitMode,
+    gravity: Gravity,
+) -> Layout {
+    let (sw, sh) = (src.0 as f64, src.1 as f64);
+    let scale = |ratio: f64| {
+        (
+            ((sw * ratio).round() as u32).max(1),
+            ((sh * ratio).round() as u32).max(1),
+        )
+    };
+
+    let (scaled, canvas) = match (width, height) {
+        (Some(w), None) => {
+            let size = scale(w as f64 / sw);
+            (size, size)
+        }
+        (None, Some(h)) => {
+            let size = scale(h as f64 / sh);
+            (size, size)
+        }
+        (Some(w), Some(h)) => {
+            let (rw, rh) = (w as f64 / sw, h as f64 / sh);
+            match fit {
+                FitMode::Fill => ((w, h), (w, h)),
+                FitMode::Inside => {
+                    let size = scale(rw.min(rh));
+                    (size, size)
+                }
+                FitMode::Contain => (scale(rw.min(rh)), (w, h)),
+                FitMode::Cover => (scale(rw.max(rh)), (w, h)),
+            }
+        }
+        (None, None) => (src, src),
+    };
+
+    let (ax, ay) = gravity.anchor();
+    Layout {
+        scaled,
+        canvas,
+        offset: (
+            (canvas.0 as i64 - scaled.0 as i64) * ax as i64 / 2,
+            (canvas.1 as i64 - scaled.1 as i64) * ay as i64 / 2,
+        ),
+    }
+}
+
+/// Final output size of a resize request
+pub fn output_size(
+    src: (u32, u32),
+    target: (Option<u32>, Option<u32>),
+    fit: FitMode,
+) -> (u32, u32) {
+    layout(src, target, fit, Gravity::Center).canvas
+}
+
+/// Check the buffers a resize allocates against `limits`, returning their
+/// memory estimate
+///
+/// A small source can be scaled up to any size, so the output is held to
+/// the same limits as a decoded image. [`FitMode::Cover`] resamples beyond
+/// the box before cropping, and that intermediate size is checked too.
+pub fn check(
+    src: (u32, u32),
+    target: (Option<u32>, Option<u32>),
+    fit: FitMode,
+    has_alpha: bool,
+    limits: &Limits,
+) -> Result<usize, ConvertError> {
+    let layout = layout(src, target, fit, Gravity::Center);
+    if layout.scaled == src && layout.canvas == layout.scaled {
+        return Ok(0);
+    }
+
+    let mut memory = limits.check(layout.scaled.0, layout.scaled.1, 8, has_alpha)?;
+    if layout.canvas != layout.scaled {
+        memory += limits.check(layout.canvas.0, layout.canvas.1, 8, has_alpha)?;
+    }
+    Ok(memory)
+}
+
+/// Resize an interleaved RGB or RGBA buffer to fit `target` (width, height)
+///
+/// `background` fills the padding added by [`FitMode::Contain`]; RGBA
+/// images are padded with transparent pixels instead. The buffer is returned
+/// untouched if no resampling is needed.
+pub fn resize(
+    pixels: Vec<u8>,
+    has_alpha: bool,
+    src: (u32, u32),
+    target: (Option<u32>, Option<u32>),
+    fit: FitMode,
+    gravity: Gravity,
+    background: [u8; 3],
+) -> Result<(Vec<u8>, u32, u32), ConvertError> {
+    let layout = layout(src, target, fit, gravity);
+    if layout.scaled == src && layout.canvas == layout.scaled {
+        return Ok((pixels, src.0, src.1));
+    }
+
+    if has_alpha {
+        resample(&pixels, src, &layout, Rgba([0, 0, 0, 0]))
+    } else {
+        resample(&pixels, src, &layout, Rgb(background))
+    }
+}
+
+fn resample<P>(
+    pixels: &[u8],
+    src: (u32, u32),
+    layout: &Layout,
+    pad: P,
+) -> Result<(Vec<u8>, u32, u32), ConvertError>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let source = ImageBuffer::<P, &[u8]>::from_raw(src.0, src.1, pixels)
+        .ok_or_else(|| ConvertError::Internal("Pixel buffer size mismatch".to_string()))?;
+    let scaled = imageops::resize(
+        &source,
+        layout.scaled.0,
+        layout.scaled.1,
+        FilterType::Lanczos3,
+    );
+
+    if layout.canvas == layout.scaled {
+        let (w, h) = scaled.dimensions();
+        return Ok((scaled.into_raw(), w, h));
+    }
+
+    let (cw, ch) = layout.canvas;
+    let mut canvas = ImageBuffer::from_pixel(cw, ch, pad);
+    imageops::replace(&mut canvas, &scaled, layout.offset.0, layout.offset.1);
+    Ok((canvas.into_raw(), cw, ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_single_side_keeps_aspect() {
+        let l = layout(
+            (4000, 3000),
+            (Some(800), None),
+            FitMode::Fill,
+            Gravity::Center,
+        );
+        assert_eq!(l.scaled, (800, 600));
+        assert_eq!(l.canvas, (800, 600));
+
+        let l = layout(
+            (4000, 3000),
+            (None, Some(300)),
+            FitMode::Cover,
+            Gravity::Center,
+        );
+        assert_eq!(l.canvas, (400, 300));
+    }
+
+    #[test]
+    fn test_layout_fit_modes() {
+        let src = (4000, 3000);
+        let l = layout(src, (Some(500), Some(500)), FitMode::Fill, Gravity::Center);
+        assert_eq!((l.scaled, l.canvas), ((500, 500), (500, 500)));
+
+        let l = layout(
+            src,
+            (Some(500), Some(500)),
+            FitMode::Inside,
+            Gravity::Center,
+        );
+        assert_eq!((l.scaled, l.canvas), ((500, 375), (500, 375)));
+
+        let l = layout(
+            src,
+            (Some(500), Some(500)),
+            FitMode::Contain,
+            Gravity::Center,
+        );
+        assert_eq!(
+            (l.scaled, l.canvas, l.offset),
+            ((500, 375), (500, 500), (0, 62))
+        );
+
+        let l = layout(src, (Some(500), Some(500)), FitMode::Cover, Gravity::Center);
+        assert_eq!(
+            (l.scaled, l.canvas, l.offset),
+            ((667, 500), (500, 500), (-83, 0))
+        );
+    }
+
+    #[test]
+    fn test_layout_gravity() {
+        let src = (4000, 3000);
+        let l = layout(src, (Some(500), Some(500)), FitMode::Cover, Gravity::East);
+        assert_eq!(l.offset, (-167, 0));
+
+        let l = layout(
+            src,
+            (Some(500), Some(500)),
+            FitMode::Contain,
+            Gravity::North,
+        );
+        assert_eq!(l.offset, (0, 0));
+        let l = layout(
+            src,
+            (Some(500), Some(500)),
+            FitMode::Contain,
+            Gravity::South,
+        );
+        assert_eq!(l.offset, (0, 125));
+    }
+
+    #[test]
+    fn test_check_limits_output() {
+        let limits = Limits {
+            max_resolution: 16384,
+            max_megapixels: 100,
+            max_job_memory: 2 << 30,
+            max_children: 100,
+        };
+        let check = |src, target, fit| check(src, target, fit, false, &limits);
+
+        assert_eq!(
+            check((4000, 3000), (None, None), FitMode::Inside).unwrap(),
+            0
+        );
+        assert_eq!(
+            check((4000, 3000), (Some(800), None), FitMode::Inside).unwrap(),
+            800 * 600 * 12
+        );
+
+        // A 1x1 source blown up to the full side limit
+        assert!(matches!(
+            check((1, 1), (Some(16384), Some(16384)), FitMode::Fill),
+            Err(ConvertError::TooManyPixels { .. })
+        ));
+        // Cover resamples a thin strip far past the box before cropping
+        assert!(matches!(
+            check((1, 1000), (Some(8000), Some(8000)), FitMode::Cover),
+            Err(ConvertError::ImageTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resize_contain_pads_with_background() {
+        let rgb = vec![0u8; 4 * 2 * 3];
+        let (out, w, h) = resize(
+            rgb,
+            false,
+            (4, 2),
+            (Some(4), Some(4)),
+            FitMode::Contain,
+            Gravity::Center,
+            [255, 255, 255],
+        )
+        .unwrap();
+        assert_eq!((w, h), (4, 4));
+        assert_eq!(&out[..3], &[255, 255, 255]);
+        // Second row holds the source pixels
+        assert_eq!(&out[12..15], &[0, 0, 0]);
+    }
+}
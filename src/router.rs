@@ -18,7 +18,10 @@ use tower_http::{
     ServiceBuilderExt,
 };
 
-use crate::handlers::{batch_info, convert_handler, health};
+use crate::handlers::{
+    batch_info, convert_handler, health, probe_handler, probe_info, stats, thumbnail_handler,
+    thumbnail_info,
+};
 use crate::state::AppState;
 
 pub fn create_router(state: Arc<AppState>) -> Router {
@@ -26,7 +29,8 @@ pub fn create_router(state: Arc<AppState>) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers(Any);
 
     // Security Headers
     // - X-Content-Type-Options: nosniff
@@ -48,6 +52,12 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         .route("/api/health", get(health))
         .route("/api/convert", post(convert_handler))
         .route("/api/info", get(batch_info))
+        .route("/api/stats", get(stats))
+        .route("/api/probe", get(probe_info).post(probe_handler))
+        .route(
+            "/api/thumbnail",
+            get(thumbnail_info).post(thumbnail_handler),
+        )
         // Static files (frontend)
         .fallback_service(ServeDir::new("static").append_index_html_on_directories(true))
         // Middleware
@@ -0,0 +1,277 @@
+//! High bit depth and HDR reduction to 8-bit
+//!
+//! Images with more than 8 bits per sample are decoded at full precision.
+//! PQ and HLG content is linearised, compressed into SDR range by the
+//! selected tone-mapping operator and re-encoded with the sRGB curve; other
+//! transfer curves are simply rescaled to 8 bits.
+
+use crate::color::{hlg_inverse_oetf, pq_eotf};
+use crate::error::ConvertError;
+use libheif_rs::TransferCharacteristics;
+use std::str::FromStr;
+
+/// Luminance in nits that SDR white is mapped from (ITU-R BT.2408)
+const REFERENCE_WHITE_NITS: f32 = 203.0;
+
+/// Peak luminance assumed for PQ content and the HLG reference display
+const HDR_PEAK_NITS: f32 = 1000.0;
+
+/// HLG system gamma for a 1000 nit display
+const HLG_SYSTEM_GAMMA: f64 = 1.2;
+
+/// Number of entries in the linear to sRGB encoding table
+const ENCODE_TABLE_SIZE: usize = 4096;
+
+/// Luma weights for BT.2020 primaries
+const LUMA_WEIGHTS: [f32; 3] = [0.2627, 0.6780, 0.0593];
+
+/// Curve used to compress HDR highlights into SDR range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    /// Hard clip everything above SDR white
+    Clip,
+    /// Extended Reinhard, reaching white at the content peak
+    Reinhard,
+    /// Hable (Uncharted 2) filmic curve
+    Hable,
+    /// Narkowicz fit of the ACES filmic curve
+    Aces,
+}
+
+impl ToneMapOperator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Clip => "clip",
+            Self::Reinhard => "reinhard",
+            Self::Hable => "hable",
+            Self::Aces => "aces",
+        }
+    }
+
+    /// Map relative luminance (1.0 = SDR white) into [0, 1]
+    fn apply(self, l: f32, peak: f32) -> f32 {
+        match self {
+            Self::Clip => l.min(1.0),
+            Self::Reinhard => l * (1.0 + l / (peak * peak)) / (1.0 + l),
+            Self::Hable => hable(l * 2.0) / hable(peak * 2.0),
+            Self::Aces => {
+                let x = l * 0.6;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }
+        }
+    }
+}
+
+impl FromStr for ToneMapOperator {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "clip" => Ok(Self::Clip),
+            "reinhard" => Ok(Self::Reinhard),
+            "hable" | "filmic" => Ok(Self::Hable),
+            "aces" => Ok(Self::Aces),
+            other => Err(ConvertError::ValidationError(format!(
+                "Invalid tone map operator: {}",
+                other
+            ))),
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+/// HDR transfer characteristics that need tone mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdrTransfer {
+    /// SMPTE ST 2084 perceptual quantizer
+    Pq,
+    /// ARIB STD-B67 hybrid log-gamma
+    Hlg,
+}
+
+impl HdrTransfer {
+    pub fn from_characteristics(transfer: TransferCharacteristics) -> Option<Self> {
+        match transfer {
+            TransferCharacteristics::ITU_R_BT_2100_0_PQ => Some(Self::Pq),
+            TransferCharacteristics::ITU_R_BT_2100_0_HLG => Some(Self::Hlg),
+            _ => None,
+        }
+    }
+
+    /// Display light relative to SDR white for a normalized signal
+    fn to_relative_linear(self, signal: f64) -> f32 {
+        let nits = match self {
+            Self::Pq => pq_eotf(signal),
+            // Per-channel approximation of the BT.2100 OOTF
+            Self::Hlg => HDR_PEAK_NITS as f64 * hlg_inverse_oetf(signal).powf(HLG_SYSTEM_GAMMA),
+        };
+        nits as f32 / REFERENCE_WHITE_NITS
+    }
+}
+
+/// Converts rows of 16-bit little-endian samples to 8-bit
+pub struct ToneMapper {
+    max_code: u16,
+    /// Code value to relative linear light, only for HDR transfers
+    linear: Option<Vec<f32>>,
+    /// Linear light in [0, 1] to 8-bit sRGB
+    encode: Vec<u8>,
+    operator: ToneMapOperator,
+    peak: f32,
+}
+
+impl ToneMapper {
+    pub fn new(bit_depth: u8, transfer: Option<HdrTransfer>, operator: ToneMapOperator) -> Self {
+        let max_code = ((1u32 << bit_depth.clamp(1, 16)) - 1) as u16;
+        let linear = transfer.map(|transfer| {
+            (0..=max_code)
+                .map(|code| transfer.to_relative_linear(code as f64 / max_code as f64))
+                .collect()
+        });
+        let encode = (0..ENCODE_TABLE_SIZE)
+            .map(|i| {
+                let v = srgb_oetf(i as f32 / (ENCODE_TABLE_SIZE - 1) as f32);
+                (v * 255.0).round() as u8
+            })
+            .collect();
+
+        Self {
+            max_code,
+            linear,
+            encode,
+            operator,
+            peak: HDR_PEAK_NITS / REFERENCE_WHITE_NITS,
+        }
+    }
+
+    /// Whether colour samples are tone-mapped rather than rescaled
+    pub fn is_hdr(&self) -> bool {
+        self.linear.is_some()
+    }
+
+    /// Convert one row of RGB or RGBA samples, appending 8-bit output to `out`
+    ///
+    /// Alpha is always rescaled linearly.
+    pub fn map_row(&self, row: &[u8], channels: usize, out: &mut Vec<u8>) {
+        for px in row.chunks_exact(channels * 2) {
+            let sample =
+                |c: usize| u16::from_le_bytes([px[2 * c], px[2 * c + 1]]).min(self.max_code);
+
+            match &self.linear {
+                Some(linear) => {
+                    let rgb = [
+                        linear[sample(0) as usize],
+                        linear[sample(1) as usize],
+                        linear[sample(2) as usize],
+                    ];
+                    for v in self.tone_map(rgb) {
+                        out.push(self.encode_linear(v));
+                    }
+                }
+                None => {
+                    for c in 0..3 {
+                        out.push(self.rescale(sample(c)));
+                    }
+                }
+            }
+
+            if channels == 4 {
+                out.push(self.rescale(sample(3)));
+            }
+        }
+    }
+
+    /// Compress luminance and scale the channels by the same factor,
+    /// which keeps hue intact
+    fn tone_map(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let l: f32 = rgb.iter().zip(LUMA_WEIGHTS).map(|(v, w)| v * w).sum();
+        if l <= 0.0 {
+            return [0.0; 3];
+        }
+        let scale = self.operator.apply(l, self.peak) / l;
+        rgb.map(|v| (v * scale).clamp(0.0, 1.0))
+    }
+
+    fn encode_linear(&self, v: f32) -> u8 {
+        self.encode[(v * (ENCODE_TABLE_SIZE - 1) as f32).round() as usize]
+    }
+
+    fn rescale(&self, code: u16) -> u8 {
+        let max = self.max_code as u32;
+        ((code as u32 * 255 + max / 2) / max) as u8
+    }
+}
+
+fn srgb_oetf(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(samples: &[u16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_operator_parse() {
+        assert_eq!(
+            "Hable".parse::<ToneMapOperator>().unwrap(),
+            ToneMapOperator::Hable
+        );
+        assert_eq!(ToneMapOperator::Aces.as_str(), "aces");
+        assert!("drago".parse::<ToneMapOperator>().is_err());
+    }
+
+    #[test]
+    fn test_operators_are_monotonic_and_bounded() {
+        let peak = HDR_PEAK_NITS / REFERENCE_WHITE_NITS;
+        for op in [
+            ToneMapOperator::Clip,
+            ToneMapOperator::Reinhard,
+            ToneMapOperator::Hable,
+            ToneMapOperator::Aces,
+        ] {
+            let mut last = op.apply(0.0, peak);
+            assert!(last.abs() < 1e-3, "{:?}", op);
+            for i in 1..=100 {
+                let v = op.apply(peak * i as f32 / 100.0, peak);
+                assert!(v >= last && v <= 1.0 + 1e-3, "{:?}", op);
+                last = v;
+            }
+        }
+    }
+
+    #[test]
+    fn test_sdr_rescale() {
+        let mapper = ToneMapper::new(10, None, ToneMapOperator::Reinhard);
+        let mut out = Vec::new();
+        mapper.map_row(&row(&[0, 512, 1023, 1023]), 4, &mut out);
+        assert_eq!(out, vec![0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn test_pq_reference_white_clips_to_full_scale() {
+        let mapper = ToneMapper::new(10, Some(HdrTransfer::Pq), ToneMapOperator::Clip);
+        assert!(mapper.is_hdr());
+        // ~203 nits; anything brighter clips
+        let mut out = Vec::new();
+        mapper.map_row(&row(&[594, 594, 594, 1023, 1023, 1023]), 3, &mut out);
+        assert!(out[..3].iter().all(|&v| v >= 253));
+        assert_eq!(&out[3..], &[255, 255, 255]);
+    }
+}
@@ -1,37 +1,48 @@
 //! Worker pool for CPU-bound image conversion
 //!
 //! Uses a dedicated Rayon thread pool for optimal CPU-bound task scheduling.
+//! Besides the thread count, jobs are admitted against a shared memory
+//! budget: each one reserves its estimated peak memory before it starts, so
+//! several huge images cannot be decoded at once.
 
 use crate::config::Config;
-use crate::converter::{convert, ConvertOptions};
+use crate::converter::{convert, estimate_memory, ConvertOptions, ConvertOutput};
 use crate::error::ConvertError;
 use rayon::ThreadPoolBuilder;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+/// The memory budget is counted in permits of this many bytes, so that
+/// any realistic budget fits the semaphore's `u32` acquisitions
+const BUDGET_UNIT: usize = 1024 * 1024;
 
 /// A conversion job
 pub struct Job {
     pub input: Vec<u8>,
     pub quality: u8,
-    pub response_tx: oneshot::Sender<Result<Vec<u8>, ConvertError>>,
+    pub options: ConvertOptions,
+    /// Estimated peak memory, reserved from the budget while the job runs
+    pub memory: usize,
+    pub response_tx: oneshot::Sender<Result<ConvertOutput, ConvertError>>,
 }
 
 /// Worker pool backed by a dedicated Rayon thread pool
 pub struct WorkerPool {
     job_tx: mpsc::Sender<Job>,
+    /// Largest reservation the budget can ever grant, in bytes
+    memory_budget: usize,
+}
+
+fn budget_units(bytes: usize) -> u32 {
+    bytes.div_ceil(BUDGET_UNIT).min(u32::MAX as usize) as u32
 }
 
 impl WorkerPool {
     /// Create a new worker pool with dedicated Rayon threads
     pub fn new(config: &Config) -> Self {
         let (job_tx, mut job_rx) = mpsc::channel::<Job>(config.queue_size);
-
-        // Create conversion options to share with workers
-        let options = Arc::new(ConvertOptions {
-            max_resolution: config.max_resolution,
-            min_quality: config.min_quality,
-            max_quality: config.max_quality,
-        });
+        let memory_budget = budget_units(config.memory_budget);
+        let budget = Arc::new(Semaphore::new(memory_budget as usize));
 
         // Build a dedicated Rayon thread pool for CPU-bound work
         let rayon_pool = Arc::new(
@@ -45,45 +56,85 @@ impl WorkerPool {
         // Spawn the async job dispatcher
         tokio::spawn(async move {
             while let Some(job) = job_rx.recv().await {
-                let opts = options.clone();
                 let pool = rayon_pool.clone();
 
+                // Wait until enough of the budget is free. Jobs start in
+                // arrival order, so a large job is not starved by smaller
+                // ones; those behind it wait in the queue meanwhile.
+                let reservation = budget
+                    .clone()
+                    .acquire_many_owned(budget_units(job.memory))
+                    .await
+                    .expect("Memory budget semaphore closed");
+
                 // Directly spawn to Rayon pool - no spawn_blocking overhead
                 pool.spawn(move || {
-                    let result = convert(&job.input, job.quality, &opts);
+                    let result = convert(&job.input, job.quality, &job.options);
+                    drop(reservation);
                     let _ = job.response_tx.send(result);
                 });
             }
         });
 
-        Self { job_tx }
+        Self {
+            job_tx,
+            memory_budget: memory_budget as usize * BUDGET_UNIT,
+        }
     }
 
     /// Submit a job for conversion
     ///
     /// # Arguments
-    /// * `input` - HEIC file bytes
-    /// * `quality` - JPEG quality (60-95)
+    /// * `input` - HEIC, AVIF, JPEG, PNG or WebP file bytes
+    /// * `quality` - Lossy encoding quality (60-95)
+    /// * `options` - Conversion limits and per-request settings
     ///
     /// # Returns
     /// * `Ok(oneshot::Receiver)` - Receiver for the result
+    /// * `Err(ConvertError::MemoryLimitExceeded)` - The job needs more than
+    ///   the whole memory budget
     /// * `Err(ConvertError::QueueFull)` - Queue is full
     pub async fn submit(
         &self,
         input: Vec<u8>,
         quality: u8,
-    ) -> Result<oneshot::Receiver<Result<Vec<u8>, ConvertError>>, ConvertError> {
+        options: ConvertOptions,
+    ) -> Result<oneshot::Receiver<Result<ConvertOutput, ConvertError>>, ConvertError> {
+        // Claim the queue slot first so a full queue is rejected without
+        // parsing anything; dropping the permit on an error releases it
+        let permit = self
+            .job_tx
+            .try_reserve()
+            .map_err(|_| ConvertError::QueueFull)?;
+
+        // Reject inputs over the limits, or too large to ever fit the
+        // budget. Reading the headers parses the whole HEIF structure, so it
+        // runs off the async runtime.
+        let (input, options, memory) = tokio::task::spawn_blocking(move || {
+            let memory = estimate_memory(&input, &options);
+            (input, options, memory)
+        })
+        .await
+        .map_err(|e| ConvertError::Internal(e.to_string()))?;
+        let memory = memory?;
+        if memory > self.memory_budget {
+            return Err(ConvertError::MemoryLimitExceeded {
+                required: memory,
+                max: self.memory_budget,
+            });
+        }
+
         let (response_tx, response_rx) = oneshot::channel();
 
         let job = Job {
             input,
             quality,
+            options,
+            memory,
             response_tx,
         };
 
-        self.job_tx
-            .try_send(job)
-            .map_err(|_| ConvertError::QueueFull)?;
+        permit.send(job);
 
         Ok(response_rx)
     }
@@ -0,0 +1,154 @@
+//! YCbCr-native JPEG path
+//!
+//! HEVC codes images as YCbCr, usually 4:2:0, which is also what JPEG
+//! stores. When no pixel-space operation is requested, the decoded planes are
+//! handed to turbojpeg's planar compressor directly, skipping the
+//! YCbCr → RGB → YCbCr round trip.
+
+use libheif_rs::{Chroma, ColorProfileNCLX, ColorSpace, MatrixCoefficients};
+use turbojpeg::Subsamp;
+
+/// A decoded image plane
+pub struct Plane<'a> {
+    pub data: &'a [u8],
+    pub stride: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// JPEG subsampling matching a libheif decoding colourspace
+pub fn jpeg_subsamp(colorspace: ColorSpace) -> Option<Subsamp> {
+    match colorspace {
+        ColorSpace::YCbCr(Chroma::C420) => Some(Subsamp::Sub2x2),
+        ColorSpace::YCbCr(Chroma::C422) => Some(Subsamp::Sub2x1),
+        ColorSpace::YCbCr(Chroma::C444) => Some(Subsamp::None),
+        ColorSpace::Monochrome => Some(Subsamp::Gray),
+        _ => None,
+    }
+}
+
+/// Whether coded YCbCr values can be stored in a JPEG unchanged
+///
+/// JFIF uses full-range BT.601 coefficients, which is also what libheif
+/// assumes when there is no nclx box.
+pub fn is_jfif_compatible(nclx: Option<&ColorProfileNCLX>) -> bool {
+    let Some(nclx) = nclx else {
+        return true;
+    };
+    nclx.full_range_flag() != 0
+        && matches!(
+            nclx.matrix_coefficients(),
+            MatrixCoefficients::ITU_R_BT_601_6 | MatrixCoefficients::ITU_R_BT_470_6_System_B_G
+        )
+}
+
+/// Luma padding turbojpeg applies to planar buffers: whole chroma samples
+fn luma_padding(subsamp: Subsamp) -> (usize, usize) {
+    match subsamp {
+        Subsamp::Sub2x2 => (2, 2),
+        Subsamp::Sub2x1 => (2, 1),
+        Subsamp::Sub1x2 => (1, 2),
+        _ => (1, 1),
+    }
+}
+
+/// Size in bytes of the buffer filled by [`pack_planes`]
+pub fn packed_len(width: usize, height: usize, subsamp: Subsamp) -> usize {
+    let (hf, vf) = luma_padding(subsamp);
+    let luma = width.div_ceil(hf) * hf * height.div_ceil(vf) * vf;
+    if subsamp == Subsamp::Gray {
+        luma
+    } else {
+        luma + 2 * luma / (hf * vf)
+    }
+}
+
+/// Pack planes into turbojpeg's planar YUV layout with a row alignment of 1
+///
+/// The luma plane is padded to a whole number of chroma samples and chroma
+/// planes are sized to match; padding repeats the last row and column.
+pub fn pack_planes(
+    planes: &[Plane],
+    width: usize,
+    height: usize,
+    subsamp: Subsamp,
+    out: &mut Vec<u8>,
+) {
+    let (hf, vf) = luma_padding(subsamp);
+    let luma_width = width.div_ceil(hf) * hf;
+    let luma_height = height.div_ceil(vf) * vf;
+
+    out.clear();
+    for (i, plane) in planes.iter().enumerate() {
+        let (pw, ph) = if i == 0 {
+            (luma_width, luma_height)
+        } else {
+            (luma_width / hf, luma_height / vf)
+        };
+        let copy = plane.width.min(pw);
+
+        out.reserve(pw * ph);
+        for y in 0..ph {
+            let start = y.min(plane.height - 1) * plane.stride;
+            let row = &plane.data[start..start + copy];
+            out.extend_from_slice(row);
+            let last = row[copy - 1];
+            out.extend(std::iter::repeat_n(last, pw - copy));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_planes_pads_odd_sizes() {
+        // 3x3 luma with a stride of 4, 2x2 chroma with a stride of 2
+        let y = [1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 9, 0];
+        let cb = [10, 11, 12, 13];
+        let cr = [20, 21, 22, 23];
+        let planes = [
+            Plane {
+                data: &y,
+                stride: 4,
+                width: 3,
+                height: 3,
+            },
+            Plane {
+                data: &cb,
+                stride: 2,
+                width: 2,
+                height: 2,
+            },
+            Plane {
+                data: &cr,
+                stride: 2,
+                width: 2,
+                height: 2,
+            },
+        ];
+
+        let mut out = Vec::new();
+        pack_planes(&planes, 3, 3, Subsamp::Sub2x2, &mut out);
+        assert_eq!(out.len(), packed_len(3, 3, Subsamp::Sub2x2));
+        assert_eq!(
+            out,
+            vec![
+                1, 2, 3, 3, 4, 5, 6, 6, 7, 8, 9, 9, 7, 8, 9, 9, // 4x4 luma
+                10, 11, 12, 13, // 2x2 Cb
+                20, 21, 22, 23, // 2x2 Cr
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jpeg_subsamp() {
+        assert_eq!(
+            jpeg_subsamp(ColorSpace::YCbCr(Chroma::C420)),
+            Some(Subsamp::Sub2x2)
+        );
+        assert_eq!(jpeg_subsamp(ColorSpace::Monochrome), Some(Subsamp::Gray));
+        assert!(is_jfif_compatible(None));
+    }
+}
@@ -45,7 +45,7 @@ function setupDragDrop() {
         e.preventDefault();
         dropZone.classList.remove('dragover');
 
-        const droppedFiles = Array.from(e.dataTransfer.files).filter(isHeicFile);
+        const droppedFiles = Array.from(e.dataTransfer.files).filter(isSupportedFile);
         addFiles(droppedFiles);
     });
 }
@@ -53,16 +53,17 @@ function setupDragDrop() {
 // File input handling
 function setupFileInput() {
     fileInput.addEventListener('change', (e) => {
-        const selectedFiles = Array.from(e.target.files).filter(isHeicFile);
+        const selectedFiles = Array.from(e.target.files).filter(isSupportedFile);
         addFiles(selectedFiles);
         fileInput.value = ''; // Reset for re-selection
     });
 }
 
-// Check if file is HEIC
-function isHeicFile(file) {
+// Check if file is HEIC, HEIF, AVIF or a common raster
+function isSupportedFile(file) {
     const name = file.name.toLowerCase();
-    return name.endsWith('.heic') || name.endsWith('.heif');
+    return ['.heic', '.heif', '.hif', '.avif', '.jpg', '.jpeg', '.png', '.webp']
+        .some(ext => name.endsWith(ext));
 }
 
 // Add files to the list
@@ -224,7 +225,7 @@ async function convertFile(file, quality) {
 
     // Get filename from Content-Disposition header or generate one
     const contentDisposition = response.headers.get('Content-Disposition');
-    let filename = file.name.replace(/\.heic$/i, '.jpg');
+    let filename = file.name.replace(/\.(heic|heif|hif|avif|jpe?g|png|webp)$/i, '.jpg');
     if (contentDisposition) {
         const match = contentDisposition.match(/filename="?([^"]+)"?/);
         if (match) filename = match[1];